@@ -1,13 +1,8 @@
-use chrono::{DateTime, Local, NaiveDate, TimeDelta};
+use chrono::{DateTime, Local, NaiveDate};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::Path, time::Duration};
+use std::{collections::HashMap, time::Duration};
 
-use crate::{
-    blocktype::BlockType,
-    err::{Error, ErrorType},
-    err_from_type, err_with_context,
-    timeblock::TimeBlock,
-};
+use crate::{blocktype::BlockType, err::Error, err_with_context, storage::Storage};
 
 #[derive(Serialize, Deserialize)]
 pub struct AnalysisQuery {
@@ -31,21 +26,20 @@ pub struct Analysis {
 
 impl Analysis {
     pub async fn get_analysis_data(
-        data_dir: &Path,
+        storage: &dyn Storage,
         start_time: DateTime<Local>,
         end_time: DateTime<Local>,
     ) -> Result<Analysis, Error> {
         let start_time = start_time.date_naive();
         let end_time = end_time.date_naive();
-        let mut blocktypes = BlockType::load(data_dir).await?;
-        blocktypes.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut blocktypes = BlockType::load(storage).await?;
+        blocktypes.sort_by_key(|b| b.id);
 
-        let mut iter_time = start_time;
         let mut durations: HashMap<u8, Duration> = HashMap::new();
         let mut trends: Vec<Trend> = Vec::new();
 
-        while iter_time <= end_time {
-            let blocks = TimeBlock::get_day_timeblocks(data_dir, iter_time).await?;
+        let days = storage.get_range_timeblocks(start_time, end_time).await?;
+        for (day, blocks) in days {
             for blocktype in &blocktypes {
                 let mut time_spent = Duration::from_secs(0);
                 for block in &blocks {
@@ -58,7 +52,7 @@ impl Analysis {
                 }
 
                 let trend = Trend {
-                    day: iter_time,
+                    day,
                     time_spent,
                     block_type_id: blocktype.id,
                 };
@@ -69,11 +63,6 @@ impl Analysis {
                     durations.insert(blocktype.id, time_spent);
                 }
             }
-
-            iter_time += TimeDelta::new(24 * 60 * 60, 0).ok_or(err_from_type!(
-                ErrorType::Chrono,
-                "Creating next day's time"
-            ))?;
         }
 
         let mut total_time = Duration::from_secs(0);
@@ -1,23 +1,63 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
+
+use crate::{
+    auth::{keys::SigningKeys, password::Password, sessions::Sessions},
+    config::AuthConfig,
+    err::Error,
+    locks::DayLocks,
+    metrics::Metrics,
+    storage::{
+        json::JsonStorage, metered::MeteredStorage, sqlite::SqliteStorage, Storage, StorageKind,
+    },
+};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
-    pub password_hash: String,
+    pub password: Password,
+    pub keys: SigningKeys,
+    pub sessions: Sessions,
+    pub metrics: Metrics,
+    pub auth: AuthConfig,
 }
 
 impl AppState {
-    pub async fn init(password_hash: String) -> Self {
-        AppState { password_hash }
+    pub async fn init(
+        password: Password,
+        keys: SigningKeys,
+        sessions: Sessions,
+        metrics: Metrics,
+        auth: AuthConfig,
+    ) -> Self {
+        AppState {
+            password,
+            keys,
+            sessions,
+            metrics,
+            auth,
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct AppData {
+    pub storage: Arc<dyn Storage>,
+    pub locks: DayLocks,
+    pub metrics: Metrics,
     pub data_dir: PathBuf,
 }
 
 impl AppData {
-    pub async fn init(data_dir: PathBuf) -> Self {
-        AppData { data_dir }
+    pub async fn init(data_dir: PathBuf, storage_kind: StorageKind) -> Result<Self, Error> {
+        let storage: Arc<dyn Storage> = match storage_kind {
+            StorageKind::Json => Arc::new(JsonStorage::new(data_dir.clone())),
+            StorageKind::Sqlite => Arc::new(SqliteStorage::open(&data_dir).await?),
+        };
+        let metrics = Metrics::new()?;
+        Ok(AppData {
+            storage: Arc::new(MeteredStorage::new(storage, metrics.clone())),
+            locks: DayLocks::default(),
+            metrics,
+            data_dir,
+        })
     }
 }
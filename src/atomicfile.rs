@@ -0,0 +1,249 @@
+use std::path::{Path, PathBuf};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    err::{Error, ErrorType},
+    err_from_type, err_with_context,
+};
+
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Files older versions replaced through `<file>.tmp`, besides the
+/// `YYYY-MM-DD.json` day files.
+const LEGACY_TARGETS: [&str; 6] = [
+    "blocktypes.json",
+    "currentblock.json",
+    "sessions.json",
+    "jwt_keys.json",
+    "password.txt",
+    "schema_version",
+];
+
+/// `<file>.<pid>.<random>.tmp` next to `path`, unique to one write so
+/// concurrent writers of the same file never share a temporary file.
+fn temp_path(path: &Path) -> Result<PathBuf, Error> {
+    let file_name = path.file_name().ok_or(err_from_type!(
+        ErrorType::InternalRustError,
+        "No file name in {}",
+        path.display()
+    ))?;
+    let mut temp_name = file_name.to_os_string();
+    temp_name.push(format!(
+        ".{}.{:016x}{}",
+        std::process::id(),
+        rand::random::<u64>(),
+        TEMP_SUFFIX
+    ));
+    Ok(path.with_file_name(temp_name))
+}
+
+/// The file a temporary file named by `temp_path` is meant to replace.
+/// `<file>.tmp`, as written by older versions, is recognised as well, but
+/// only for the data files they wrote that way. Any other `.tmp` file is not
+/// ours and `None`.
+fn target_of(temp_name: &str) -> Option<&str> {
+    let stem = temp_name.strip_suffix(TEMP_SUFFIX)?;
+    let mut parts = stem.rsplitn(3, '.');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(random), Some(pid), Some(target))
+            if !random.is_empty()
+                && random.chars().all(|c| c.is_ascii_hexdigit())
+                && !pid.is_empty()
+                && pid.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            Some(target)
+        }
+        _ if LEGACY_TARGETS.contains(&stem) || is_day_file(stem) => Some(stem),
+        _ => None,
+    }
+}
+
+fn is_day_file(name: &str) -> bool {
+    name.strip_suffix(".json")
+        .is_some_and(|day| chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").is_ok())
+}
+
+/// Flush a directory entry change (create, rename) to disk.
+pub async fn sync_dir(dir: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        let dir_file = tokio::fs::File::open(dir)
+            .await
+            .map_err(|e| err_with_context!(e, "Opening {}", dir.display()))?;
+        dir_file
+            .sync_all()
+            .await
+            .map_err(|e| err_with_context!(e, "Syncing {}", dir.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Replace `path` with `contents` so that after a crash the file holds either
+/// the old or the new contents, never a mix. The data goes to a temporary
+/// file next to `path`, is synced to disk and then renamed over `path`.
+pub async fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), Error> {
+    let temp = temp_path(path)?;
+    let mut file = tokio::fs::File::create(&temp)
+        .await
+        .map_err(|e| err_with_context!(e, "Creating {}", temp.display()))?;
+    file.write_all(contents.as_ref())
+        .await
+        .map_err(|e| err_with_context!(e, "Writing {}", temp.display()))?;
+    file.sync_all()
+        .await
+        .map_err(|e| err_with_context!(e, "Syncing {}", temp.display()))?;
+    drop(file);
+    tokio::fs::rename(&temp, path)
+        .await
+        .map_err(|e| err_with_context!(e, "Renaming {} to {}", temp.display(), path.display()))?;
+    if let Some(parent) = path.parent() {
+        sync_dir(parent).await?;
+    }
+    Ok(())
+}
+
+/// Clean up temporary files left in `dir` by writes interrupted by a crash.
+/// Must not run while anything else writes to `dir`.
+///
+/// A temporary file whose target is missing and which holds valid JSON was
+/// fully synced before the crash and is moved into place, the newest one if
+/// several were left for the same target. Anything else is incomplete or
+/// superseded and removed; the target still holds its previous contents.
+pub async fn recover(dir: &Path) -> Result<(), Error> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .map_err(|e| err_with_context!(e, "Reading {}", dir.display()))?;
+    let mut temps = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| err_with_context!(e, "Reading {}", dir.display()))?
+    {
+        let Some(target_name) = entry
+            .file_name()
+            .to_str()
+            .and_then(target_of)
+            .map(|name| name.to_string())
+        else {
+            continue;
+        };
+        let modified = entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        temps.push((modified, entry.path(), target_name));
+    }
+    temps.sort_by_key(|(modified, ..)| std::cmp::Reverse(*modified));
+
+    for (_, temp, target_name) in temps {
+        let target = dir.join(target_name);
+        let complete = match tokio::fs::read(&temp).await {
+            Ok(content) => serde_json::from_slice::<serde_json::Value>(&content).is_ok(),
+            Err(_) => false,
+        };
+        if complete && !target.exists() {
+            tracing::warn!(target = %target.display(), temp = %temp.display(), "Recovering interrupted write");
+            tokio::fs::rename(&temp, &target).await.map_err(|e| {
+                err_with_context!(e, "Renaming {} to {}", temp.display(), target.display())
+            })?;
+        } else {
+            tracing::warn!(temp = %temp.display(), "Removing interrupted write");
+            tokio::fs::remove_file(&temp)
+                .await
+                .map_err(|e| err_with_context!(e, "Removing {}", temp.display()))?;
+        }
+    }
+    sync_dir(dir).await
+}
+
+/// Run `recover` on every directory the server writes data files to.
+pub async fn recover_data_dir(data_dir: &Path) -> Result<(), Error> {
+    recover(data_dir).await?;
+    recover(&data_dir.join("timeblocks")).await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn check(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn temp_names_map_back_to_their_target() {
+        let temp = temp_path(Path::new("/data/blocktypes.json")).ok().unwrap();
+        let temp_name = temp.file_name().unwrap().to_str().unwrap();
+        assert_ne!(
+            temp,
+            temp_path(Path::new("/data/blocktypes.json")).ok().unwrap()
+        );
+        assert_eq!(target_of(temp_name), Some("blocktypes.json"));
+        assert_eq!(target_of("2024-06-12.json.tmp"), Some("2024-06-12.json"));
+        assert_eq!(target_of("2024-06-12.json"), None);
+        assert_eq!(target_of("sessions.json.tmp"), Some("sessions.json"));
+        assert_eq!(target_of("notes.txt.tmp"), None);
+        assert_eq!(target_of("backup.tmp"), None);
+        assert_eq!(target_of("notes.txt.12.zz.tmp"), None);
+    }
+
+    #[tokio::test]
+    async fn interrupted_writes_are_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let created = dir.path().join("created.json");
+        let replaced = dir.path().join("replaced.json");
+        write(&replaced, "[1]").await.ok().unwrap();
+
+        // Crashed after syncing the first version of a new file, and while
+        // writing a replacement of an existing one.
+        std::fs::write(temp_path(&created).ok().unwrap(), "[2]").unwrap();
+        std::fs::write(temp_path(&replaced).ok().unwrap(), "[3").unwrap();
+        // Someone else's file.
+        std::fs::write(dir.path().join("notes.tmp"), "[4]").unwrap();
+        recover(dir.path()).await.ok().unwrap();
+
+        assert_eq!(
+            check(dir.path()),
+            ["created.json", "notes.tmp", "replaced.json"]
+        );
+        assert_eq!(std::fs::read_to_string(&created).unwrap(), "[2]");
+        assert_eq!(std::fs::read_to_string(&replaced).unwrap(), "[1]");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writers_never_mix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("currentblock.json");
+        let contents = (0..16)
+            .map(|i| serde_json::to_string(&vec![i; 10_000]).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut tasks = Vec::new();
+        for content in contents.clone() {
+            let path = path.clone();
+            tasks.push(tokio::spawn(
+                async move { write(&path, content).await.is_ok() },
+            ));
+        }
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(&written));
+        assert_eq!(check(dir.path()), ["currentblock.json"]);
+    }
+}
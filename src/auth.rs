@@ -1,3 +1,6 @@
 pub mod controller;
 pub mod handlers;
+pub mod keys;
 pub mod middleware;
+pub mod password;
+pub mod sessions;
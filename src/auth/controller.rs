@@ -1,26 +1,141 @@
-use jsonwebtoken::{decode, DecodingKey};
+use jsonwebtoken::{decode, decode_header, encode, Header, Validation};
 
-use crate::err::Error;
+use crate::{config::AuthConfig, err::Error, err_with_context};
 
 use super::{
-    handlers::{Claims, LoginRequest},
+    handlers::{Claims, LoginRequest, LoginResponse, TokenType},
+    keys::SigningKeys,
     middleware::TokenState,
+    password::Password,
 };
 
-pub fn verify_user(login_info: &LoginRequest, password_hash: &str) -> bool {
-    login_info.key == password_hash
+/// `sub` of every token. The server has a single user.
+const SUBJECT: &str = "owner";
+
+pub async fn verify_user(login_info: &LoginRequest, password: &Password) -> Result<bool, Error> {
+    password.verify(&login_info.password).await
+}
+
+fn claims(typ: TokenType, lifetime: chrono::Duration, sid: &str) -> Claims {
+    let now = chrono::Utc::now();
+    Claims {
+        sub: SUBJECT.to_string(),
+        typ,
+        iat: now.timestamp() as usize,
+        exp: (now + lifetime).timestamp() as usize,
+        jti: uuid::Uuid::new_v4().to_string(),
+        sid: sid.to_string(),
+    }
+}
+
+pub struct TokenPair {
+    pub tokens: LoginResponse,
+    /// Claims of the refresh token, for the session to remember.
+    pub refresh: Claims,
+}
+
+/// A fresh access and refresh token pair for session `sid`, signed with the
+/// current key.
+pub fn issue_tokens(keys: &SigningKeys, auth: &AuthConfig, sid: &str) -> Result<TokenPair, Error> {
+    let (kid, key) = keys.encoding_key()?;
+    let header = Header {
+        kid: Some(kid),
+        ..Header::default()
+    };
+
+    let access_claims = claims(TokenType::Access, auth.access_token_lifetime(), sid);
+    let access_token = encode(&header, &access_claims, &key)
+        .map_err(|e| err_with_context!(e, "Error creating access token"))?;
+
+    let refresh_claims = claims(TokenType::Refresh, auth.refresh_token_lifetime(), sid);
+    let refresh_token = encode(&header, &refresh_claims, &key)
+        .map_err(|e| err_with_context!(e, "Error creating refresh token"))?;
+
+    Ok(TokenPair {
+        tokens: LoginResponse {
+            access_token,
+            refresh_token,
+        },
+        refresh: refresh_claims,
+    })
 }
 
-pub fn verify_token(token: &str, password_hash: &str) -> Result<TokenState, Error> {
-    match decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(password_hash.as_bytes()),
-        &Default::default(),
-    ) {
-        Ok(_) => Ok(TokenState::Valid),
+/// Check `token`'s signature, expiry and that it is of type `expected`, so a
+/// refresh token never works as an access token or the other way round.
+pub fn verify_token(
+    token: &str,
+    expected: TokenType,
+    keys: &SigningKeys,
+) -> Result<TokenState, Error> {
+    let Some(kid) = decode_header(token).ok().and_then(|header| header.kid) else {
+        return Ok(TokenState::Unauthorized);
+    };
+    let Some(key) = keys.decoding_key(&kid)? else {
+        return Ok(TokenState::Unauthorized);
+    };
+    let mut validation = Validation::default();
+    validation.set_required_spec_claims(&["exp", "sub"]);
+    validation.sub = Some(SUBJECT.to_string());
+    // Issuer and verifier share a clock, the default minute of leeway would
+    // only stretch short access token lifetimes.
+    validation.leeway = 0;
+    match decode::<Claims>(token, &key, &validation) {
+        Ok(data) if data.claims.typ == expected => Ok(TokenState::Valid(data.claims)),
+        Ok(_) => Ok(TokenState::Unauthorized),
         Err(e) => match e.kind() {
             jsonwebtoken::errors::ErrorKind::ExpiredSignature => Ok(TokenState::Expired),
             _ => Ok(TokenState::Unauthorized),
         },
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn check(token: &str, expected: TokenType, keys: &SigningKeys) -> bool {
+        matches!(
+            verify_token(token, expected, keys).ok().unwrap(),
+            TokenState::Valid(_)
+        )
+    }
+
+    #[test]
+    fn tokens_only_work_as_their_own_type() {
+        let keys = SigningKeys::ephemeral();
+        let tokens = issue_tokens(&keys, &AuthConfig::default(), "phone")
+            .ok()
+            .unwrap()
+            .tokens;
+
+        assert!(check(&tokens.access_token, TokenType::Access, &keys));
+        assert!(!check(&tokens.access_token, TokenType::Refresh, &keys));
+        assert!(check(&tokens.refresh_token, TokenType::Refresh, &keys));
+        assert!(!check(&tokens.refresh_token, TokenType::Access, &keys));
+        assert!(!check(
+            &tokens.access_token,
+            TokenType::Access,
+            &SigningKeys::ephemeral()
+        ));
+
+        let TokenState::Valid(access) =
+            verify_token(&tokens.access_token, TokenType::Access, &keys)
+                .ok()
+                .unwrap()
+        else {
+            panic!("access token rejected");
+        };
+        let TokenState::Valid(refresh) =
+            verify_token(&tokens.refresh_token, TokenType::Refresh, &keys)
+                .ok()
+                .unwrap()
+        else {
+            panic!("refresh token rejected");
+        };
+        assert_eq!(access.sub, SUBJECT);
+        assert_eq!(access.sid, refresh.sid);
+        assert_ne!(access.jti, refresh.jti);
+        assert_eq!(access.exp - access.iat, 30);
+    }
+}
@@ -1,27 +1,29 @@
 use axum::{
-    body::Body,
-    http::{Response, StatusCode},
+    http::{header::USER_AGENT, HeaderMap, StatusCode},
     response::IntoResponse,
     Extension, Json,
 };
-use chrono::Duration;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header};
+use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     app::AppState,
     err::{Error, ErrorType},
-    err_from_type, err_with_context,
+    err_from_type, extract,
 };
 
 use super::{
-    controller::{verify_token, verify_user},
+    controller::{issue_tokens, verify_token, verify_user},
     middleware::TokenState,
+    sessions::Rotation,
 };
 
 #[derive(Deserialize)]
 pub struct LoginRequest {
-    pub key: String,
+    pub password: String,
+    /// Name shown in the session list, the `User-Agent` if absent.
+    #[serde(default)]
+    pub device: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -30,54 +32,51 @@ pub struct LoginResponse {
     pub refresh_token: String,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    /// Sent as the bearer token with every API request.
+    Access,
+    /// Only accepted by `/auth/refresh`.
+    Refresh,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Claims {
+    /// Who the token was issued to. There is a single user.
+    pub sub: String,
+    pub typ: TokenType,
+    pub iat: usize,
     pub exp: usize,
+    /// Unique id of this token.
+    pub jti: String,
+    /// Id of the session the token belongs to.
+    pub sid: String,
 }
 
 #[axum_macros::debug_handler]
 pub async fn login(
     Extension(state): Extension<AppState>,
-    Json(login_info): Json<LoginRequest>,
+    headers: HeaderMap,
+    extract::Json(login_info): extract::Json<LoginRequest>,
 ) -> Result<impl IntoResponse, impl IntoResponse> {
-    if verify_user(&login_info, &state.password_hash) {
-        let access_claims = Claims {
-            exp: (chrono::Utc::now() + Duration::seconds(30)).timestamp() as usize,
-        };
-
-        let access_token = match encode(
-            &Header::default(),
-            &access_claims,
-            &EncodingKey::from_secret(login_info.key.as_bytes()),
-        ) {
-            Ok(token) => token,
-            Err(e) => {
-                println!("Erron creating token: {}", e);
-                return Err(err_with_context!(e, "Error creating access token"));
-            }
-        };
-
-        let refresh_claims = Claims {
-            exp: (chrono::Utc::now() + Duration::days(7)).timestamp() as usize,
-        };
-
-        let refresh_token = match encode(
-            &Header::default(),
-            &refresh_claims,
-            &EncodingKey::from_secret(login_info.key.as_bytes()),
-        ) {
-            Ok(token) => token,
-            Err(e) => {
-                println!("Erron creating token: {}", e);
-                return Err(err_with_context!(e, "Error creating refresh token"));
-            }
-        };
-
-        Ok(Json(LoginResponse {
-            access_token,
-            refresh_token,
-        }))
+    if verify_user(&login_info, &state.password).await? {
+        let device = login_info
+            .device
+            .clone()
+            .or_else(|| {
+                headers
+                    .get(USER_AGENT)
+                    .and_then(|agent| agent.to_str().ok())
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        let sid = uuid::Uuid::new_v4().to_string();
+        let pair = issue_tokens(&state.keys, &state.auth, &sid)?;
+        state.sessions.create(device, &pair.refresh).await?;
+        Ok(Json(pair.tokens))
     } else {
+        state.metrics.auth_failure("login");
         Err(err_from_type!(ErrorType::Unauthorized))
     }
 }
@@ -86,42 +85,29 @@ pub async fn login(
 pub async fn refresh_token(
     Extension(state): Extension<AppState>,
     token: String,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    match decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(state.password_hash.as_bytes()),
-        &Default::default(),
-    ) {
-        Ok(_) => {
-            let access_claims = Claims {
-                exp: (chrono::Utc::now() + Duration::seconds(30)).timestamp() as usize,
-            };
-
-            let access_token = encode(
-                &Header::default(),
-                &access_claims,
-                &EncodingKey::from_secret(state.password_hash.as_bytes()),
-            )
-            .map_err(|e| err_with_context!(e, "Error creating access token"))?;
-
-            let refresh_claims = Claims {
-                exp: (chrono::Utc::now() + Duration::days(7)).timestamp() as usize,
-            };
-
-            let refresh_token = encode(
-                &Header::default(),
-                &refresh_claims,
-                &EncodingKey::from_secret(state.password_hash.as_bytes()),
-            )
-            .map_err(|e| err_with_context!(e, "Error creating refresh token"))?;
-
-            Ok(Json(LoginResponse {
-                access_token,
-                refresh_token,
-            }))
+) -> Result<impl IntoResponse, Error> {
+    match verify_token(&token, TokenType::Refresh, &state.keys)? {
+        TokenState::Valid(presented) => {
+            let pair = issue_tokens(&state.keys, &state.auth, &presented.sid)?;
+            match state.sessions.rotate(&presented, &pair.refresh).await? {
+                Rotation::Rotated => Ok(Json(pair.tokens)),
+                Rotation::Reused => {
+                    tracing::warn!(session = %presented.sid, "Refresh token reused, revoked session");
+                    state.metrics.auth_failure("reuse");
+                    Err(err_from_type!(
+                        ErrorType::Unauthorized,
+                        "Refresh token already used"
+                    ))
+                }
+                Rotation::Unknown => {
+                    state.metrics.auth_failure("revoked");
+                    Err(err_from_type!(ErrorType::Unauthorized, "Session revoked"))
+                }
+            }
         }
-        Err(e) => {
-            println!("Erron decoding token: {}", e);
+        TokenState::Expired | TokenState::Unauthorized => {
+            tracing::warn!("Rejected refresh token");
+            state.metrics.auth_failure("refresh");
             Err(err_from_type!(
                 ErrorType::Unauthorized,
                 "Unauthorized Access on token refresh"
@@ -130,27 +116,106 @@ pub async fn refresh_token(
     }
 }
 
+#[derive(Deserialize)]
+pub struct RotateKeyQuery {
+    /// Seconds tokens signed by the old key keep working, the configured
+    /// `key_grace_secs` if absent.
+    pub grace_secs: Option<u32>,
+}
+
+/// Sign new tokens with a fresh key. Answers with tokens signed by it so the
+/// caller stays logged in even without a grace window.
+#[axum_macros::debug_handler]
+pub async fn rotate_key(
+    Extension(state): Extension<AppState>,
+    Extension(claims): Extension<Claims>,
+    extract::Query(query): extract::Query<RotateKeyQuery>,
+) -> Result<impl IntoResponse, Error> {
+    let grace_secs = query.grace_secs.unwrap_or(state.auth.key_grace_secs);
+    state
+        .keys
+        .rotate(chrono::Duration::seconds(i64::from(grace_secs)))
+        .await?;
+    let pair = issue_tokens(&state.keys, &state.auth, &claims.sid)?;
+    state.sessions.reissue(&pair.refresh).await?;
+    Ok(Json(pair.tokens))
+}
+
 #[axum_macros::debug_handler]
 pub async fn check_token(
     Extension(state): Extension<AppState>,
     token: String,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    match verify_token(&token, &state.password_hash) {
-        Ok(token_state) => match token_state {
-            TokenState::Valid => Ok(StatusCode::OK),
-            TokenState::Expired => Err(Response::builder()
-                .status(StatusCode::NETWORK_AUTHENTICATION_REQUIRED)
-                .body(Body::from(
-                    err_from_type!(ErrorType::TokenExpired, "Access token timed out").to_string(),
-                ))
-                .map_err(|e| err_with_context!(e, "Building for unauthorized request"))),
-            TokenState::Unauthorized => Err(Response::builder()
-                .status(StatusCode::UNAUTHORIZED)
-                .body(Body::from(
-                    err_from_type!(ErrorType::Unauthorized, "Unauthorized request").to_string(),
-                ))
-                .map_err(|e| err_with_context!(e, "Building for unauthorized request"))),
-        },
-        Err(e) => Err(Err(e)),
+) -> Result<impl IntoResponse, Error> {
+    match verify_token(&token, TokenType::Access, &state.keys)? {
+        TokenState::Valid(claims) if state.sessions.is_active(&claims.sid).await => {
+            Ok(StatusCode::OK)
+        }
+        TokenState::Valid(_) => Err(err_from_type!(ErrorType::Unauthorized, "Session revoked")),
+        TokenState::Expired => Err(err_from_type!(
+            ErrorType::TokenExpired,
+            "Access token timed out"
+        )),
+        TokenState::Unauthorized => Err(err_from_type!(
+            ErrorType::Unauthorized,
+            "Unauthorized request"
+        )),
     }
 }
+
+/// End the session the access token belongs to.
+#[axum_macros::debug_handler]
+pub async fn logout(
+    Extension(state): Extension<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, Error> {
+    state.sessions.revoke(&claims.sid).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub device: String,
+    pub created: DateTime<Local>,
+    pub last_refreshed: DateTime<Local>,
+    pub expires: DateTime<Local>,
+    /// Whether this is the session making the request.
+    pub current: bool,
+}
+
+#[axum_macros::debug_handler]
+pub async fn list_sessions(
+    Extension(state): Extension<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Json<Vec<SessionInfo>> {
+    let sessions = state
+        .sessions
+        .list()
+        .await
+        .into_iter()
+        .map(|session| SessionInfo {
+            current: session.id == claims.sid,
+            id: session.id,
+            device: session.device,
+            created: session.created,
+            last_refreshed: session.last_refreshed,
+            expires: session.expires,
+        })
+        .collect();
+    Json(sessions)
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionRequest {
+    pub id: String,
+}
+
+/// End any session, for signing out a lost device.
+#[axum_macros::debug_handler]
+pub async fn revoke_session(
+    Extension(state): Extension<AppState>,
+    extract::Json(request): extract::Json<RevokeSessionRequest>,
+) -> Result<impl IntoResponse, Error> {
+    state.sessions.revoke(&request.id).await?;
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,240 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, Duration, Local};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    atomicfile,
+    err::{Error, ErrorType},
+    err_from_type, err_with_context,
+};
+
+const KEYS_FILE: &str = "jwt_keys.json";
+const SECRET_LEN: usize = 64;
+
+/// One JWT signing secret, named by the `kid` header of the tokens it signs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SigningKey {
+    id: String,
+    secret: String,
+    created: DateTime<Local>,
+    /// Set once the key is rotated out. Tokens it signed are accepted until
+    /// then.
+    valid_until: Option<DateTime<Local>>,
+}
+
+impl SigningKey {
+    fn generate() -> Self {
+        SigningKey {
+            id: uuid::Uuid::new_v4().to_string(),
+            secret: OsRng
+                .sample_iter(&Alphanumeric)
+                .take(SECRET_LEN)
+                .map(char::from)
+                .collect(),
+            created: Local::now(),
+            valid_until: None,
+        }
+    }
+
+    fn is_valid(&self, now: DateTime<Local>) -> bool {
+        self.valid_until.is_none_or(|until| now < until)
+    }
+}
+
+/// Every key still accepted, the one signing new tokens last.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeySet {
+    keys: Vec<SigningKey>,
+}
+
+impl KeySet {
+    fn current(&self) -> Result<&SigningKey, Error> {
+        self.keys
+            .last()
+            .filter(|key| key.valid_until.is_none())
+            .ok_or_else(|| err_from_type!(ErrorType::InternalRustError, "No current signing key"))
+    }
+
+    /// Retire the current key, accepting its tokens for `grace` longer, and
+    /// start signing with a new one. Keys past their grace window are
+    /// dropped.
+    fn rotate(&mut self, grace: Duration) {
+        let now = Local::now();
+        for key in &mut self.keys {
+            if key.valid_until.is_none() {
+                key.valid_until = Some(now + grace);
+            }
+        }
+        self.keys.retain(|key| key.is_valid(now));
+        self.keys.push(SigningKey::generate());
+    }
+}
+
+/// The JWT signing secrets, kept in `jwt_keys.json` in the data directory and
+/// unrelated to the password.
+#[derive(Debug, Clone)]
+pub struct SigningKeys {
+    path: PathBuf,
+    set: Arc<RwLock<KeySet>>,
+    /// Held for a whole rotation. `set` is only replaced by rotations, so
+    /// holding this from reading the keys until the new set is in place
+    /// keeps concurrent rotations from losing a key.
+    rotation: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl SigningKeys {
+    /// Read the keys from `data_dir`, generating the first one if there are
+    /// none yet.
+    pub async fn load_or_create(data_dir: &Path) -> Result<Self, Error> {
+        let path = data_dir.join(KEYS_FILE);
+        let set = if path.exists() {
+            read(&path).await?
+        } else {
+            let set = KeySet {
+                keys: vec![SigningKey::generate()],
+            };
+            save(&path, &set).await?;
+            tracing::info!(path = %path.display(), "Generated JWT signing key");
+            set
+        };
+        Ok(SigningKeys {
+            path,
+            set: Arc::new(RwLock::new(set)),
+            rotation: Arc::default(),
+        })
+    }
+
+    /// A single fresh key that is never written anywhere.
+    #[cfg(test)]
+    pub fn ephemeral() -> Self {
+        SigningKeys {
+            path: PathBuf::new(),
+            set: Arc::new(RwLock::new(KeySet {
+                keys: vec![SigningKey::generate()],
+            })),
+            rotation: Arc::default(),
+        }
+    }
+
+    fn read(&self) -> Result<KeySet, Error> {
+        self.set
+            .read()
+            .map(|set| set.clone())
+            .map_err(|_| err_from_type!(ErrorType::InternalRustError, "Signing keys lock poisoned"))
+    }
+
+    /// The id and key new tokens are signed with.
+    pub fn encoding_key(&self) -> Result<(String, EncodingKey), Error> {
+        let set = self.read()?;
+        let key = set.current()?;
+        Ok((
+            key.id.clone(),
+            EncodingKey::from_secret(key.secret.as_bytes()),
+        ))
+    }
+
+    /// The key for verifying a token signed by `id`, `None` if it is unknown
+    /// or past its grace window.
+    pub fn decoding_key(&self, id: &str) -> Result<Option<DecodingKey>, Error> {
+        let set = self.read()?;
+        let now = Local::now();
+        Ok(set
+            .keys
+            .iter()
+            .find(|key| key.id == id && key.is_valid(now))
+            .map(|key| DecodingKey::from_secret(key.secret.as_bytes())))
+    }
+
+    /// Start signing with a new key. Tokens signed by the old one stop
+    /// working after `grace`, so every session has to log in again unless
+    /// it refreshes in time.
+    ///
+    /// Starts from the keys on disk rather than those in memory, so nothing
+    /// written to the file since it was loaded is overwritten.
+    pub async fn rotate(&self, grace: Duration) -> Result<(), Error> {
+        let _rotation = self.rotation.lock().await;
+        let mut set = read(&self.path).await?;
+        set.rotate(grace);
+        save(&self.path, &set).await?;
+        *self.set.write().map_err(|_| {
+            err_from_type!(ErrorType::InternalRustError, "Signing keys lock poisoned")
+        })? = set;
+        tracing::info!(grace_secs = grace.num_seconds(), "Rotated JWT signing key");
+        Ok(())
+    }
+}
+
+async fn read(path: &Path) -> Result<KeySet, Error> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| err_with_context!(e, "Reading {}", path.display()))?;
+    serde_json::from_str(&content).map_err(|e| err_with_context!(e, "Parsing {}", path.display()))
+}
+
+async fn save(path: &Path, set: &KeySet) -> Result<(), Error> {
+    let content = serde_json::to_string_pretty(set)
+        .map_err(|e| err_with_context!(e, "Serializing signing keys"))?;
+    atomicfile::write(path, content).await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn check(set: &KeySet, id: &str) -> bool {
+        set.keys
+            .iter()
+            .any(|key| key.id == id && key.is_valid(Local::now()))
+    }
+
+    #[test]
+    fn rotation_keeps_old_keys_for_the_grace_window() {
+        let mut set = KeySet {
+            keys: vec![SigningKey::generate()],
+        };
+        let first = set.current().ok().unwrap().id.clone();
+        assert_eq!(set.current().ok().unwrap().secret.len(), SECRET_LEN);
+
+        set.rotate(Duration::minutes(5));
+        let second = set.current().ok().unwrap().id.clone();
+        assert_ne!(first, second);
+        assert!(check(&set, &first));
+        assert!(check(&set, &second));
+
+        set.rotate(Duration::zero());
+        let third = set.current().ok().unwrap().id.clone();
+        assert!(check(&set, &first));
+        assert!(!check(&set, &second));
+        assert!(check(&set, &third));
+
+        set.rotate(Duration::zero());
+        assert_eq!(set.keys.len(), 2);
+        assert!(!check(&set, &third));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_rotations_keep_every_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = SigningKeys::load_or_create(dir.path()).await.ok().unwrap();
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let keys = keys.clone();
+            tasks.push(tokio::spawn(async move {
+                keys.rotate(Duration::minutes(5)).await.is_ok()
+            }));
+        }
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+        let on_disk = read(&dir.path().join(KEYS_FILE)).await.ok().unwrap();
+        assert_eq!(on_disk.keys.len(), 9);
+        assert_eq!(keys.read().ok().unwrap().keys.len(), 9);
+    }
+}
@@ -1,62 +1,64 @@
-use axum::{
-    body::Body,
-    extract::State,
-    http::{Response, StatusCode},
-    middleware::Next,
-    response::IntoResponse,
-};
+use axum::{body::Body, extract::State, middleware::Next, response::IntoResponse};
 
 use crate::{
     app::AppState,
-    auth::controller::verify_token,
+    auth::{
+        controller::verify_token,
+        handlers::{Claims, TokenType},
+    },
     err::{Error, ErrorType},
-    err_from_type, err_with_context,
+    err_from_type,
 };
 
 pub enum TokenState {
-    Valid,
+    Valid(Claims),
     Expired,
     Unauthorized,
 }
 
+/// Let requests with a valid access token through, making its `Claims`
+/// available to handlers as an extension.
 pub async fn auth_middleware(
     State(app_state): State<AppState>,
-    req: axum::http::Request<Body>,
+    mut req: axum::http::Request<Body>,
     next: Next,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let headers = req.headers();
-    if let Some(auth_header) = headers.get(axum::http::header::AUTHORIZATION) {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if let Some(bearer_token) = auth_str.strip_prefix("Bearer ") {
-                match verify_token(bearer_token, &app_state.password_hash)? {
-                    TokenState::Valid => return Ok(next.run(req).await),
-                    TokenState::Expired => {
-                        return Response::builder()
-                            .status(StatusCode::NETWORK_AUTHENTICATION_REQUIRED)
-                            .body(Body::from(
-                                err_from_type!(ErrorType::TokenExpired, "Access token timed out")
-                                    .to_string(),
-                            ))
-                            .map_err(|e| err_with_context!(e, "Building for unauthorized request"))
-                    }
-                    TokenState::Unauthorized => {
-                        return Response::builder()
-                            .status(StatusCode::UNAUTHORIZED)
-                            .body(Body::from(
-                                err_from_type!(ErrorType::Unauthorized, "Unauthorized request")
-                                    .to_string(),
-                            ))
-                            .map_err(|e| err_with_context!(e, "Building for unauthorized request"))
-                    }
+) -> Result<impl IntoResponse, Error> {
+    let bearer_token = req
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|auth_header| auth_header.to_str().ok())
+        .and_then(|auth_str| auth_str.strip_prefix("Bearer "))
+        .map(|bearer_token| bearer_token.to_string());
+    if let Some(bearer_token) = bearer_token {
+        match verify_token(&bearer_token, TokenType::Access, &app_state.keys)? {
+            TokenState::Valid(claims) => {
+                if !app_state.sessions.is_active(&claims.sid).await {
+                    app_state.metrics.auth_failure("revoked");
+                    return Err(err_from_type!(ErrorType::Unauthorized, "Session revoked"));
                 }
+                req.extensions_mut().insert(claims);
+                return Ok(next.run(req).await);
+            }
+            TokenState::Expired => {
+                app_state.metrics.auth_failure("expired");
+                return Err(err_from_type!(
+                    ErrorType::TokenExpired,
+                    "Access token timed out"
+                ));
+            }
+            TokenState::Unauthorized => {
+                app_state.metrics.auth_failure("invalid");
+                return Err(err_from_type!(
+                    ErrorType::Unauthorized,
+                    "Unauthorized request"
+                ));
             }
         }
     }
-    println!("Unauthorized request");
-    Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .body(Body::from(
-            err_from_type!(ErrorType::Unauthorized, "Unauthorized request").to_string(),
-        ))
-        .map_err(|e| err_with_context!(e, "Building for unauthorized request"))
+    tracing::warn!("Request without bearer token");
+    app_state.metrics.auth_failure("missing");
+    Err(err_from_type!(
+        ErrorType::Unauthorized,
+        "Unauthorized request"
+    ))
 }
@@ -0,0 +1,141 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use argon2::{
+    password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::rngs::OsRng;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    atomicfile,
+    err::{Error, ErrorType},
+    err_from_type, err_with_context,
+};
+
+const PASSWORD_FILE: &str = "password.txt";
+
+/// Hash `password` with Argon2id and a fresh random salt, as a PHC string.
+pub fn hash(password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| err_with_context!(e, "Hashing password"))
+}
+
+/// Hashes written before Argon2id are bare sha256 hex digests; PHC strings
+/// start with `$`.
+fn is_legacy(stored: &str) -> bool {
+    !stored.starts_with('$')
+}
+
+/// Whether `password` matches `stored`, comparing in constant time.
+pub fn verify(password: &str, stored: &str) -> Result<bool, Error> {
+    if is_legacy(stored) {
+        let digest = sha256::digest(password);
+        return Ok(digest.as_bytes().ct_eq(stored.as_bytes()).into());
+    }
+    let stored =
+        PasswordHash::new(stored).map_err(|e| err_with_context!(e, "Parsing password hash"))?;
+    match Argon2::default().verify_password(password.as_bytes(), &stored) {
+        Ok(()) => Ok(true),
+        Err(password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(err_with_context!(e, "Verifying password")),
+    }
+}
+
+/// The login password, kept hashed in `password.txt` in the data directory.
+#[derive(Debug, Clone)]
+pub struct Password {
+    path: PathBuf,
+    hash: Arc<RwLock<String>>,
+}
+
+impl Password {
+    pub async fn load(data_dir: &Path) -> Result<Self, Error> {
+        let path = data_dir.join(PASSWORD_FILE);
+        let hash = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", path.display()))?;
+        Ok(Password {
+            path,
+            hash: Arc::new(RwLock::new(hash.trim().to_string())),
+        })
+    }
+
+    /// Store `password` in `data_dir`, replacing any previous one.
+    pub async fn set(data_dir: &Path, password: &str) -> Result<(), Error> {
+        let password = password.to_string();
+        let hash = tokio::task::spawn_blocking(move || hash(&password))
+            .await
+            .map_err(|e| {
+                err_from_type!(ErrorType::InternalRustError, "Joining hash task: {}", e)
+            })??;
+        atomicfile::write(&data_dir.join(PASSWORD_FILE), hash).await
+    }
+
+    fn hash(&self) -> Result<String, Error> {
+        self.hash
+            .read()
+            .map(|hash| hash.clone())
+            .map_err(|_| err_from_type!(ErrorType::InternalRustError, "Password lock poisoned"))
+    }
+
+    /// Check a login attempt. A legacy sha256 hash is replaced by an Argon2id
+    /// one the first time the right password is given.
+    pub async fn verify(&self, password: &str) -> Result<bool, Error> {
+        let stored = self.hash()?;
+        let password = password.to_string();
+        let (valid, upgraded) = tokio::task::spawn_blocking(move || {
+            let valid = verify(&password, &stored)?;
+            let upgraded = if valid && is_legacy(&stored) {
+                Some(hash(&password)?)
+            } else {
+                None
+            };
+            Ok::<_, Error>((valid, upgraded))
+        })
+        .await
+        .map_err(|e| {
+            err_from_type!(ErrorType::InternalRustError, "Joining verify task: {}", e)
+        })??;
+
+        if let Some(upgraded) = upgraded {
+            atomicfile::write(&self.path, &upgraded).await?;
+            *self.hash.write().map_err(|_| {
+                err_from_type!(ErrorType::InternalRustError, "Password lock poisoned")
+            })? = upgraded;
+            tracing::info!(path = %self.path.display(), "Upgraded password hash to Argon2id");
+        }
+        Ok(valid)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn check(password: &str, stored: &str) -> bool {
+        verify(password, stored).ok().unwrap()
+    }
+
+    #[test]
+    fn verifies_argon2_and_legacy_hashes() {
+        let stored = hash("hunter2").ok().unwrap();
+        assert!(stored.starts_with("$argon2id$"));
+        assert_ne!(stored, hash("hunter2").ok().unwrap());
+        assert!(check("hunter2", &stored));
+        assert!(!check("hunter3", &stored));
+
+        let legacy = sha256::digest("hunter2");
+        assert!(is_legacy(&legacy));
+        assert!(check("hunter2", &legacy));
+        assert!(!check("hunter3", &legacy));
+        assert!(!check(&legacy, &legacy));
+    }
+}
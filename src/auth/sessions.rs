@@ -0,0 +1,301 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    atomicfile,
+    err::{Error, ErrorType},
+    err_from_type, err_with_context,
+};
+
+use super::handlers::Claims;
+
+const SESSIONS_FILE: &str = "sessions.json";
+
+/// One login on one device. Every refresh replaces its refresh token, so only
+/// the most recently issued one is accepted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub device: String,
+    pub created: DateTime<Local>,
+    pub last_refreshed: DateTime<Local>,
+    pub expires: DateTime<Local>,
+    /// `jti` of the refresh token that may be used next.
+    refresh_jti: String,
+}
+
+/// What presenting a refresh token did to its session.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// The token was the latest one and has been replaced.
+    Rotated,
+    /// The token had already been replaced, so it was stolen or replayed.
+    /// The session has been revoked.
+    Reused,
+    /// The session was revoked or has expired.
+    Unknown,
+}
+
+fn expiry(claims: &Claims) -> Result<DateTime<Local>, Error> {
+    Local
+        .timestamp_opt(claims.exp as i64, 0)
+        .single()
+        .ok_or_else(|| {
+            err_from_type!(
+                ErrorType::Chrono,
+                "Token expiry {} out of range",
+                claims.exp
+            )
+        })
+}
+
+/// Sessions that have logged in and not been revoked, kept in
+/// `sessions.json` in the data directory.
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    /// `None` keeps sessions in memory only.
+    path: Option<PathBuf>,
+    sessions: Arc<Mutex<Vec<Session>>>,
+}
+
+impl Sessions {
+    pub async fn load(data_dir: &Path) -> Result<Self, Error> {
+        let path = data_dir.join(SESSIONS_FILE);
+        let sessions = if path.exists() {
+            let content = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|e| err_with_context!(e, "Reading {}", path.display()))?;
+            serde_json::from_str(&content)
+                .map_err(|e| err_with_context!(e, "Parsing {}", path.display()))?
+        } else {
+            Vec::new()
+        };
+        Ok(Sessions {
+            path: Some(path),
+            sessions: Arc::new(Mutex::new(sessions)),
+        })
+    }
+
+    /// Drop expired sessions from `next`, write it and only then make it the
+    /// current state, so a failed write changes nothing. Called with the lock
+    /// held so writes never interleave.
+    async fn save(&self, current: &mut Vec<Session>, mut next: Vec<Session>) -> Result<(), Error> {
+        let now = Local::now();
+        next.retain(|session| session.expires > now);
+        if let Some(path) = &self.path {
+            let content = serde_json::to_string_pretty(&next)
+                .map_err(|e| err_with_context!(e, "Serializing sessions"))?;
+            atomicfile::write(path, content).await?;
+        }
+        *current = next;
+        Ok(())
+    }
+
+    /// Start a session for `device`, whose first refresh token is `refresh`.
+    pub async fn create(&self, device: String, refresh: &Claims) -> Result<(), Error> {
+        let now = Local::now();
+        let mut sessions = self.sessions.lock().await;
+        let mut next = sessions.clone();
+        next.push(Session {
+            id: refresh.sid.clone(),
+            device,
+            created: now,
+            last_refreshed: now,
+            expires: expiry(refresh)?,
+            refresh_jti: refresh.jti.clone(),
+        });
+        self.save(&mut sessions, next).await
+    }
+
+    /// Replace the refresh token `presented` by `next` if it is still the
+    /// latest of its session, revoking the session if it is not.
+    pub async fn rotate(&self, presented: &Claims, next: &Claims) -> Result<Rotation, Error> {
+        let now = Local::now();
+        let mut sessions = self.sessions.lock().await;
+        let Some(idx) = sessions
+            .iter()
+            .position(|session| session.id == presented.sid && session.expires > now)
+        else {
+            return Ok(Rotation::Unknown);
+        };
+        let mut updated = sessions.clone();
+        if updated[idx].refresh_jti != presented.jti {
+            updated.remove(idx);
+            if let Err(e) = self.save(&mut sessions, updated).await {
+                // Revoke in memory anyway: failing to persist a revocation
+                // must not keep a stolen token working.
+                sessions.remove(idx);
+                return Err(e);
+            }
+            return Ok(Rotation::Reused);
+        }
+        let session = &mut updated[idx];
+        session.refresh_jti = next.jti.clone();
+        session.last_refreshed = now;
+        session.expires = expiry(next)?;
+        self.save(&mut sessions, updated).await?;
+        Ok(Rotation::Rotated)
+    }
+
+    /// Make `next` the refresh token of its session without presenting the
+    /// previous one, for tokens handed out to an already authenticated
+    /// caller.
+    pub async fn reissue(&self, next: &Claims) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().await;
+        let mut updated = sessions.clone();
+        let session = updated
+            .iter_mut()
+            .find(|session| session.id == next.sid)
+            .ok_or_else(|| err_from_type!(ErrorType::NotFound, "No session {}", next.sid))?;
+        session.refresh_jti = next.jti.clone();
+        session.last_refreshed = Local::now();
+        session.expires = expiry(next)?;
+        self.save(&mut sessions, updated).await
+    }
+
+    pub async fn is_active(&self, id: &str) -> bool {
+        let now = Local::now();
+        self.sessions
+            .lock()
+            .await
+            .iter()
+            .any(|session| session.id == id && session.expires > now)
+    }
+
+    pub async fn list(&self) -> Vec<Session> {
+        let now = Local::now();
+        self.sessions
+            .lock()
+            .await
+            .iter()
+            .filter(|session| session.expires > now)
+            .cloned()
+            .collect()
+    }
+
+    /// End session `id`. Its access and refresh tokens stop working at once.
+    pub async fn revoke(&self, id: &str) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().await;
+        let mut next = sessions.clone();
+        next.retain(|session| session.id != id);
+        if next.len() == sessions.len() {
+            return Err(err_from_type!(ErrorType::NotFound, "No session {}", id));
+        }
+        self.save(&mut sessions, next).await
+    }
+
+    /// Sessions kept in memory only.
+    #[cfg(test)]
+    pub fn ephemeral() -> Self {
+        Sessions {
+            path: None,
+            sessions: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::auth::handlers::TokenType;
+
+    fn refresh_claims(sid: &str) -> Claims {
+        let now = Local::now().timestamp() as usize;
+        Claims {
+            sub: "owner".to_string(),
+            typ: TokenType::Refresh,
+            iat: now,
+            exp: now + 60,
+            jti: uuid::Uuid::new_v4().to_string(),
+            sid: sid.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reusing_a_rotated_refresh_token_revokes_the_session() {
+        let sessions = Sessions::ephemeral();
+        let first = refresh_claims("phone");
+        sessions
+            .create("phone".to_string(), &first)
+            .await
+            .ok()
+            .unwrap();
+        assert!(sessions.is_active("phone").await);
+
+        let second = refresh_claims("phone");
+        let rotation = sessions.rotate(&first, &second).await.ok().unwrap();
+        assert_eq!(rotation, Rotation::Rotated);
+        let third = refresh_claims("phone");
+        let rotation = sessions.rotate(&second, &third).await.ok().unwrap();
+        assert_eq!(rotation, Rotation::Rotated);
+
+        let rotation = sessions
+            .rotate(&first, &refresh_claims("phone"))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(rotation, Rotation::Reused);
+        assert!(!sessions.is_active("phone").await);
+        let rotation = sessions
+            .rotate(&third, &refresh_claims("phone"))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(rotation, Rotation::Unknown);
+
+        let laptop = refresh_claims("laptop");
+        sessions
+            .create("laptop".to_string(), &laptop)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(sessions.list().await.len(), 1);
+        assert!(sessions.revoke("laptop").await.is_ok());
+        assert!(sessions.revoke("laptop").await.is_err());
+        assert!(sessions.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_saves_leave_sessions_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let working = Sessions::load(dir.path()).await.ok().unwrap();
+        let first = refresh_claims("phone");
+        working
+            .create("phone".to_string(), &first)
+            .await
+            .ok()
+            .unwrap();
+
+        // Same sessions, written somewhere that cannot be written to.
+        let failing = Sessions {
+            path: Some(dir.path().join("missing").join(SESSIONS_FILE)),
+            sessions: working.sessions.clone(),
+        };
+        let second = refresh_claims("phone");
+        assert!(failing.rotate(&first, &second).await.is_err());
+        assert!(failing
+            .create("laptop".to_string(), &refresh_claims("laptop"))
+            .await
+            .is_err());
+        assert!(failing.revoke("phone").await.is_err());
+        assert_eq!(working.list().await.len(), 1);
+
+        // The client never got `second`, so retrying with `first` is fine.
+        let rotation = working.rotate(&first, &second).await.ok().unwrap();
+        assert_eq!(rotation, Rotation::Rotated);
+        let reloaded = Sessions::load(dir.path()).await.ok().unwrap();
+        let rotation = reloaded
+            .rotate(&second, &refresh_claims("phone"))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(rotation, Rotation::Rotated);
+    }
+}
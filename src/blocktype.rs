@@ -1,8 +1,6 @@
-use std::path::Path;
-
 use crate::{
     err::{Error, ErrorType},
-    err_with_context,
+    storage::Storage,
 };
 use serde::{Deserialize, Serialize};
 
@@ -43,7 +41,7 @@ impl PushNew<NewBlockType> for Vec<BlockType> {
 }
 
 impl BlockType {
-    pub async fn save(data_dir: &Path, types: &[Self]) -> Result<(), Error> {
+    pub async fn save(storage: &dyn Storage, types: &[Self]) -> Result<(), Error> {
         if Self::check_identical(types) {
             return Err(Error {
                 error_type: ErrorType::IdenticalBlockType,
@@ -53,32 +51,22 @@ impl BlockType {
                 additional: None,
             });
         }
-        let blocktypes_path = data_dir.join("blocktypes.json");
-        let contents = serde_json::to_string_pretty(&types)
-            .map_err(|e| err_with_context!(e, "Serializing to {}", blocktypes_path.display()))?;
-        tokio::fs::write(&blocktypes_path, contents)
-            .await
-            .map_err(|e| err_with_context!(e, "Writing to blocktypes.json"))?;
-        Ok(())
+        storage.save_blocktypes(types).await
     }
 
-    pub async fn load(data_dir: &Path) -> Result<Vec<Self>, Error> {
-        let blocktypes_path = data_dir.join("blocktypes.json");
-        if !blocktypes_path.exists() {
-            let blocktypes = vec![BlockType {
-                id: 0,
-                name: "System".to_string(),
-                color: Color { r: 0, g: 0, b: 255 },
-            }];
-            BlockType::save(data_dir, &blocktypes).await?;
-            return Ok(blocktypes);
+    pub async fn load(storage: &dyn Storage) -> Result<Vec<Self>, Error> {
+        match storage.load_blocktypes().await? {
+            Some(blocktypes) => Ok(blocktypes),
+            None => {
+                let blocktypes = vec![BlockType {
+                    id: 0,
+                    name: "System".to_string(),
+                    color: Color { r: 0, g: 0, b: 255 },
+                }];
+                BlockType::save(storage, &blocktypes).await?;
+                Ok(blocktypes)
+            }
         }
-        let content = tokio::fs::read_to_string(&blocktypes_path)
-            .await
-            .map_err(|e| err_with_context!(e, "Reading {}", blocktypes_path.display()))?;
-        let blocktypes = serde_json::from_str::<Vec<Self>>(&content)
-            .map_err(|e| err_with_context!(e, "Deserializing {}", blocktypes_path.display()))?;
-        Ok(blocktypes)
     }
 
     fn check_identical(blocktypes: &[Self]) -> bool {
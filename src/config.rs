@@ -0,0 +1,319 @@
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use serde::Deserialize;
+use tower_http::cors::CorsLayer;
+
+use crate::{logging::LogFormat, storage::StorageKind};
+
+/// Environment variable naming the config file.
+pub const CONFIG_ENV: &str = "TIME_SCHEDULER_CONFIG";
+
+/// Server settings. Read from a TOML file, then overridden by
+/// `TIME_SCHEDULER_*` environment variables, then by the command line.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: Option<PathBuf>,
+    /// Only needed when listening on `bind` addresses.
+    pub port: Option<u16>,
+    /// Addresses to listen on. Empty to listen only on `unix_socket`.
+    pub bind: Vec<IpAddr>,
+    /// Also listen on a unix domain socket, e.g. behind a local reverse proxy.
+    pub unix_socket: Option<PathBuf>,
+    pub storage: StorageKind,
+    /// Fail instead of prompting for anything missing.
+    pub non_interactive: bool,
+    /// How long requests still running on shutdown get to finish.
+    pub shutdown_timeout_secs: u32,
+    pub auth: AuthConfig,
+    pub log: LogConfig,
+    pub cors: CorsConfig,
+    pub metrics: MetricsConfig,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: None,
+            port: None,
+            bind: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            unix_socket: None,
+            storage: StorageKind::default(),
+            non_interactive: false,
+            shutdown_timeout_secs: 30,
+            auth: AuthConfig::default(),
+            log: LogConfig::default(),
+            cors: CorsConfig::default(),
+            metrics: MetricsConfig::default(),
+            tls: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub access_token_lifetime_secs: u32,
+    pub refresh_token_lifetime_secs: u32,
+    /// How long tokens signed by a rotated out key keep working.
+    pub key_grace_secs: u32,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            access_token_lifetime_secs: 30,
+            refresh_token_lifetime_secs: 7 * 24 * 60 * 60,
+            key_grace_secs: 5 * 60,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn access_token_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::seconds(i64::from(self.access_token_lifetime_secs))
+    }
+
+    pub fn refresh_token_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::seconds(i64::from(self.refresh_token_lifetime_secs))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Level or filter directives in `RUST_LOG` syntax.
+    pub level: Option<String>,
+    pub format: Option<LogFormat>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API from a browser. Empty disables CORS.
+    pub allowed_origins: Vec<String>,
+}
+
+impl CorsConfig {
+    pub fn layer(&self) -> Result<Option<CorsLayer>, Box<dyn std::error::Error>> {
+        if self.allowed_origins.is_empty() {
+            return Ok(None);
+        }
+        let origins = self
+            .allowed_origins
+            .iter()
+            .map(|origin| HeaderValue::from_str(origin))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods([Method::GET, Method::POST])
+                .allow_headers([
+                    header::AUTHORIZATION,
+                    header::CONTENT_TYPE,
+                    header::IF_MATCH,
+                ])
+                .expose_headers([header::ETAG, HeaderName::from_static("x-request-id")]),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address serving `/metrics` without a login, for Prometheus. The
+    /// metrics include the running block, so keep it private. Everywhere
+    /// else `/metrics` needs an access token.
+    pub bind: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain. Reloaded when the file changes.
+    pub cert: PathBuf,
+    /// PEM private key. Reloaded when the file changes.
+    pub key: PathBuf,
+    /// Also listen for plain HTTP on this port and redirect it to HTTPS.
+    #[serde(default)]
+    pub http_redirect_port: Option<u16>,
+}
+
+/// `name`'s value, treating an unset or empty variable as absent.
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_var<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    var(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| format!("Invalid {}: {}", name, e))
+        })
+        .transpose()
+}
+
+impl Config {
+    /// Read `path`, or start from the defaults without one, and apply the
+    /// environment on top.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Reading config {}: {}", path.display(), e))?;
+                toml::from_str(&content)
+                    .map_err(|e| format!("Parsing config {}: {}", path.display(), e))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(data_dir) = var("TIME_SCHEDULER_DATA_DIR") {
+            self.data_dir = Some(PathBuf::from(data_dir));
+        }
+        if let Some(port) = parse_var("TIME_SCHEDULER_PORT")? {
+            self.port = Some(port);
+        }
+        if let Some(bind) = var("TIME_SCHEDULER_BIND") {
+            self.bind = bind
+                .split(',')
+                .map(|ip| ip.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("Invalid TIME_SCHEDULER_BIND: {}", e))?;
+        }
+        if let Some(unix_socket) = var("TIME_SCHEDULER_UNIX_SOCKET") {
+            self.unix_socket = Some(PathBuf::from(unix_socket));
+        }
+        if let Some(storage) = parse_var("TIME_SCHEDULER_STORAGE")? {
+            self.storage = storage;
+        }
+        if let Some(non_interactive) = parse_var("TIME_SCHEDULER_NON_INTERACTIVE")? {
+            self.non_interactive = non_interactive;
+        }
+        if let Some(secs) = parse_var("TIME_SCHEDULER_SHUTDOWN_TIMEOUT_SECS")? {
+            self.shutdown_timeout_secs = secs;
+        }
+        if let Some(secs) = parse_var("TIME_SCHEDULER_ACCESS_TOKEN_LIFETIME_SECS")? {
+            self.auth.access_token_lifetime_secs = secs;
+        }
+        if let Some(secs) = parse_var("TIME_SCHEDULER_REFRESH_TOKEN_LIFETIME_SECS")? {
+            self.auth.refresh_token_lifetime_secs = secs;
+        }
+        if let Some(secs) = parse_var("TIME_SCHEDULER_KEY_GRACE_SECS")? {
+            self.auth.key_grace_secs = secs;
+        }
+        if let Some(level) = var(crate::logging::LEVEL_ENV) {
+            self.log.level = Some(level);
+        }
+        if let Some(format) = parse_var(crate::logging::FORMAT_ENV)? {
+            self.log.format = Some(format);
+        }
+        if let Some(origins) = var("TIME_SCHEDULER_CORS_ORIGINS") {
+            self.cors.allowed_origins = origins.split(',').map(|o| o.trim().to_string()).collect();
+        }
+        if let Some(bind) = parse_var("TIME_SCHEDULER_METRICS_BIND")? {
+            self.metrics.bind = Some(bind);
+        }
+        match (
+            var("TIME_SCHEDULER_TLS_CERT"),
+            var("TIME_SCHEDULER_TLS_KEY"),
+        ) {
+            (Some(cert), Some(key)) => {
+                let http_redirect_port = self.tls.as_ref().and_then(|t| t.http_redirect_port);
+                self.tls = Some(TlsConfig {
+                    cert: PathBuf::from(cert),
+                    key: PathBuf::from(key),
+                    http_redirect_port,
+                })
+            }
+            (None, None) => {}
+            _ => {
+                return Err(
+                    "TIME_SCHEDULER_TLS_CERT and TIME_SCHEDULER_TLS_KEY must be set together"
+                        .into(),
+                )
+            }
+        }
+        if let Some(port) = parse_var("TIME_SCHEDULER_HTTP_REDIRECT_PORT")? {
+            self.tls
+                .as_mut()
+                .ok_or("TIME_SCHEDULER_HTTP_REDIRECT_PORT needs TLS to be configured")?
+                .http_redirect_port = Some(port);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn config_file_is_strict() {
+        let config: Config = toml::from_str(
+            r#"
+            data_dir = "/srv/time"
+            port = 8080
+            bind = ["127.0.0.1", "::1"]
+            unix_socket = "/run/time-scheduler.sock"
+            storage = "sqlite"
+
+            [auth]
+            access_token_lifetime_secs = 60
+
+            [log]
+            format = "json"
+
+            [cors]
+            allowed_origins = ["https://time.example"]
+
+            [metrics]
+            bind = "127.0.0.1:9090"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.port, Some(8080));
+        assert_eq!(
+            config.bind,
+            vec![
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            ]
+        );
+        assert_eq!(
+            config.unix_socket,
+            Some(PathBuf::from("/run/time-scheduler.sock"))
+        );
+        assert_eq!(config.storage, StorageKind::Sqlite);
+        assert_eq!(config.auth.access_token_lifetime_secs, 60);
+        assert_eq!(config.auth.refresh_token_lifetime_secs, 7 * 24 * 60 * 60);
+        assert_eq!(config.log.format, Some(LogFormat::Json));
+        assert!(config.cors.layer().unwrap().is_some());
+        assert_eq!(
+            config.metrics.bind,
+            Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 9090)))
+        );
+        assert!(Config::default().metrics.bind.is_none());
+
+        assert!(toml::from_str::<Config>("prot = 8080").is_err());
+        assert!(toml::from_str::<Config>("[log]\nlevle = \"debug\"").is_err());
+    }
+}
@@ -1,38 +1,34 @@
 use serde::{Deserialize, Serialize};
-use std::path::Path;
 
-use crate::{err::Error, err_with_context};
+use crate::{blocktype::BlockType, err::Error, storage::Storage, violations::Violations};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CurrentBlock {
     pub block_type_id: u8,
     pub current_block_name: String,
 }
 
 impl CurrentBlock {
-    pub async fn get(data_dir: &Path) -> Result<Self, Error> {
-        let current_block_file = data_dir.join("currentblock.json");
-        if !current_block_file.exists() {
-            return Ok(CurrentBlock {
+    pub async fn get(storage: &dyn Storage) -> Result<Self, Error> {
+        match storage.get_current_block().await? {
+            Some(current_block) => Ok(current_block),
+            None => Ok(CurrentBlock {
                 block_type_id: 0,
                 current_block_name: "Hello for first setup".to_string(),
-            });
+            }),
         }
-        let currrent_data_file = tokio::fs::read_to_string(&current_block_file)
-            .await
-            .map_err(|e| err_with_context!(e, "Reading {}", current_block_file.display()))?;
-        let res = serde_json::from_str(&currrent_data_file)
-            .map_err(|e| err_with_context!(e, "Deserializing {}", current_block_file.display()))?;
-        Ok(res)
     }
 
-    pub async fn save(&self, data_dir: &Path) -> Result<(), Error> {
-        let current_block_file = data_dir.join("currentblock.json");
-        let data = serde_json::to_string(self)
-            .map_err(|e| err_with_context!(e, "Serializing {}", current_block_file.display()))?;
-        tokio::fs::write(&current_block_file, data)
-            .await
-            .map_err(|e| err_with_context!(e, "Writing {}", current_block_file.display()))?;
-        Ok(())
+    /// Fail with `Validation` unless the block has a name and an existing
+    /// block type.
+    pub fn check(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        let mut violations = Violations::default();
+        violations.title("current_block_name", &self.current_block_name);
+        violations.block_type("block_type_id", self.block_type_id, blocktypes);
+        violations.into_result()
+    }
+
+    pub async fn save(&self, storage: &dyn Storage) -> Result<(), Error> {
+        storage.save_current_block(self).await
     }
 }
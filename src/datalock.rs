@@ -0,0 +1,61 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::Path,
+};
+
+use crate::{
+    err::{Error, ErrorType},
+    err_from_type, err_with_context,
+};
+
+pub const LOCK_FILE: &str = "server.lock";
+
+/// Exclusive hold on a data directory, taken by the server for as long as it
+/// runs and by commands that write to the directory, so they never write at
+/// the same time. The operating system releases it when the holder exits,
+/// even after a crash.
+#[derive(Debug)]
+pub struct DataDirLock {
+    _file: File,
+}
+
+impl DataDirLock {
+    /// Take the lock on `data_dir`, failing at once if it is held.
+    pub fn acquire(data_dir: &Path) -> Result<Self, Error> {
+        let path = data_dir.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| err_with_context!(e, "Opening {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => Ok(DataDirLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(err_from_type!(
+                ErrorType::DataDirInUse,
+                "{} is in use by a running server, stop it first",
+                data_dir.display()
+            )),
+            Err(TryLockError::Error(e)) => Err(err_with_context!(e, "Locking {}", path.display())),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_holder_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = DataDirLock::acquire(dir.path()).ok().unwrap();
+        let res = DataDirLock::acquire(dir.path());
+        assert!(matches!(
+            res.map_err(|e| e.error_type),
+            Err(ErrorType::DataDirInUse)
+        ));
+        drop(lock);
+        assert!(DataDirLock::acquire(dir.path()).is_ok());
+    }
+}
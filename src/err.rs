@@ -2,9 +2,13 @@ use std::fmt::{self, Display};
 
 use axum::{
     body::Body,
-    http::{Response, StatusCode},
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::{header, Response, StatusCode},
     response::IntoResponse,
 };
+use serde_json::{json, Value};
+
+use crate::violations::FieldError;
 
 #[macro_export]
 macro_rules! err_from_type {
@@ -49,12 +53,28 @@ pub enum ErrorType {
     SerdeError(serde_json::Error),
     Tokio(tokio::io::Error),
     Jwt(jsonwebtoken::errors::Error),
+    Sqlite(rusqlite::Error),
+    Prometheus(prometheus::Error),
+    PasswordHash(argon2::password_hash::Error),
     Chrono,
     IdenticalBlockType,
     NotFound,
     InternalRustError,
     TokenExpired,
     Unauthorized,
+    SchemaVersion,
+    DataDirInUse,
+    PreconditionFailed,
+    NotAdjacent,
+    NoNeighbour,
+    Overlap,
+    InvalidTimeRange,
+    SwallowsNeighbour,
+    NegativeNeighbour,
+    Validation(Vec<FieldError>),
+    /// A request body or query string that could not be parsed, with the
+    /// status axum would have answered.
+    Rejection(StatusCode),
 }
 
 impl Display for ErrorType {
@@ -64,12 +84,89 @@ impl Display for ErrorType {
             ErrorType::SerdeError(error) => write!(f, "Serde error: {}", error),
             ErrorType::Tokio(error) => write!(f, "Tokio error: {}", error),
             ErrorType::Jwt(error) => write!(f, "JWT error: {}", error),
+            ErrorType::Sqlite(error) => write!(f, "SQLite error: {}", error),
+            ErrorType::Prometheus(error) => write!(f, "Prometheus error: {}", error),
+            ErrorType::PasswordHash(error) => write!(f, "Password hash error: {}", error),
             ErrorType::Chrono => write!(f, "Chrono error"),
             ErrorType::IdenticalBlockType => write!(f, "Blocktypes Identical"),
             ErrorType::NotFound => write!(f, "Timeblock Not Found"),
             ErrorType::InternalRustError => write!(f, "Internal Rust error"),
             ErrorType::TokenExpired => write!(f, "Access Token timed out"),
             ErrorType::Unauthorized => write!(f, "Unauthorized Access"),
+            ErrorType::SchemaVersion => write!(f, "Data schema version mismatch"),
+            ErrorType::DataDirInUse => write!(f, "Data directory in use"),
+            ErrorType::PreconditionFailed => write!(f, "Day data changed since it was read"),
+            ErrorType::NotAdjacent => write!(f, "Timeblocks Not Adjacent"),
+            ErrorType::NoNeighbour => write!(f, "Timeblock Has No Neighbour"),
+            ErrorType::Overlap => write!(f, "Timeblocks Overlap"),
+            ErrorType::InvalidTimeRange => write!(f, "Invalid Time Range"),
+            ErrorType::SwallowsNeighbour => write!(f, "Timeblock Swallows Neighbour"),
+            ErrorType::NegativeNeighbour => write!(f, "Neighbour Timeblock Goes Negative"),
+            ErrorType::Validation(_) => write!(f, "Invalid Request"),
+            ErrorType::Rejection(_) => write!(f, "Malformed Request"),
+        }
+    }
+}
+
+impl ErrorType {
+    /// Stable identifier clients can match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorType::AxumError(_) => "http_error",
+            ErrorType::SerdeError(_) => "serde_error",
+            ErrorType::Tokio(_) => "io_error",
+            ErrorType::Jwt(_) => "jwt_error",
+            ErrorType::Sqlite(_) => "sqlite_error",
+            ErrorType::Prometheus(_) => "metrics_error",
+            ErrorType::PasswordHash(_) => "password_hash_error",
+            ErrorType::Chrono => "chrono_error",
+            ErrorType::IdenticalBlockType => "identical_block_type",
+            ErrorType::NotFound => "not_found",
+            ErrorType::InternalRustError => "internal_error",
+            ErrorType::TokenExpired => "token_expired",
+            ErrorType::Unauthorized => "unauthorized",
+            ErrorType::SchemaVersion => "schema_version",
+            ErrorType::DataDirInUse => "data_dir_in_use",
+            ErrorType::PreconditionFailed => "precondition_failed",
+            ErrorType::NotAdjacent => "not_adjacent",
+            ErrorType::NoNeighbour => "no_neighbour",
+            ErrorType::Overlap => "overlap",
+            ErrorType::InvalidTimeRange => "invalid_time_range",
+            ErrorType::SwallowsNeighbour => "swallows_neighbour",
+            ErrorType::NegativeNeighbour => "negative_neighbour",
+            ErrorType::Validation(_) => "validation_failed",
+            ErrorType::Rejection(_) => "malformed_request",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorType::Rejection(status) => *status,
+            ErrorType::Unauthorized => StatusCode::UNAUTHORIZED,
+            // Clients refresh their access token on this status.
+            ErrorType::TokenExpired => StatusCode::NETWORK_AUTHENTICATION_REQUIRED,
+            ErrorType::NotFound => StatusCode::NOT_FOUND,
+            ErrorType::IdenticalBlockType
+            | ErrorType::NotAdjacent
+            | ErrorType::NoNeighbour
+            | ErrorType::Overlap
+            | ErrorType::SwallowsNeighbour
+            | ErrorType::NegativeNeighbour => StatusCode::CONFLICT,
+            ErrorType::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorType::InvalidTimeRange | ErrorType::Validation(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ErrorType::AxumError(_)
+            | ErrorType::SerdeError(_)
+            | ErrorType::Tokio(_)
+            | ErrorType::Jwt(_)
+            | ErrorType::Sqlite(_)
+            | ErrorType::Prometheus(_)
+            | ErrorType::PasswordHash(_)
+            | ErrorType::Chrono
+            | ErrorType::InternalRustError
+            | ErrorType::SchemaVersion
+            | ErrorType::DataDirInUse => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
@@ -97,16 +194,74 @@ impl Display for Error {
 }
 
 impl IntoResponse for Error {
+    /// Answers with `{"code", "message", "details"}`. Server errors keep
+    /// their message and details out of the response; the source location
+    /// only ever goes to the log.
     #[allow(clippy::unwrap_used)]
     fn into_response(self) -> Response<Body> {
-        let status_code = StatusCode::INTERNAL_SERVER_ERROR;
+        let status_code = self.error_type.status();
+        let additional = self.additional.as_deref().unwrap_or_default();
+        if status_code.is_server_error() {
+            tracing::error!(
+                file = self.file,
+                line = self.line,
+                column = self.column,
+                code = self.error_type.code(),
+                additional,
+                "{}",
+                self.error_type
+            );
+        } else {
+            tracing::warn!(
+                file = self.file,
+                line = self.line,
+                column = self.column,
+                code = self.error_type.code(),
+                additional,
+                "{}",
+                self.error_type
+            );
+        }
+        let (message, details) = if status_code.is_server_error() {
+            ("Internal Server Error".to_string(), Value::Null)
+        } else if let ErrorType::Validation(details) = &self.error_type {
+            (self.error_type.to_string(), json!(details))
+        } else {
+            (self.error_type.to_string(), json!(self.additional))
+        };
+        let body = json!({
+            "code": self.error_type.code(),
+            "message": message,
+            "details": details,
+        });
         Response::builder()
             .status(status_code)
-            .body(Body::from(self.to_string()))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
             .unwrap()
     }
 }
 
+impl From<JsonRejection> for Error {
+    fn from(rejection: JsonRejection) -> Self {
+        err_from_type!(
+            ErrorType::Rejection(rejection.status()),
+            "{}",
+            rejection.body_text()
+        )
+    }
+}
+
+impl From<QueryRejection> for Error {
+    fn from(rejection: QueryRejection) -> Self {
+        err_from_type!(
+            ErrorType::Rejection(rejection.status()),
+            "{}",
+            rejection.body_text()
+        )
+    }
+}
+
 impl<E> From<(E, &'static str, u32, u32, Option<String>)> for Error
 where
     E: Into<ErrorType>,
@@ -160,3 +315,65 @@ impl From<jsonwebtoken::errors::Error> for ErrorType {
         ErrorType::Jwt(err)
     }
 }
+
+impl From<rusqlite::Error> for ErrorType {
+    fn from(err: rusqlite::Error) -> Self {
+        ErrorType::Sqlite(err)
+    }
+}
+
+impl From<prometheus::Error> for ErrorType {
+    fn from(err: prometheus::Error) -> Self {
+        ErrorType::Prometheus(err)
+    }
+}
+
+impl From<argon2::password_hash::Error> for ErrorType {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        ErrorType::PasswordHash(err)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    async fn respond(error: Error) -> (StatusCode, Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn responses_are_structured_without_source_location() {
+        let (status, body) = respond(err_from_type!(
+            ErrorType::NotFound,
+            "Time block {} not found",
+            7
+        ))
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body,
+            json!({
+                "code": "not_found",
+                "message": "Timeblock Not Found",
+                "details": "Time block 7 not found",
+            })
+        );
+
+        let (status, body) = respond(err_from_type!(
+            ErrorType::InternalRustError,
+            "Reading /secret/path"
+        ))
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["details"], Value::Null);
+        assert!(!body.to_string().contains("err.rs"));
+        assert!(!body.to_string().contains("/secret/path"));
+    }
+}
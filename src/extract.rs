@@ -0,0 +1,14 @@
+use axum::extract::{FromRequest, FromRequestParts};
+
+use crate::err::Error;
+
+/// `axum::Json` answering a malformed body with our error format.
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(Error))]
+pub struct Json<T>(pub T);
+
+/// `axum::extract::Query` answering a malformed query string with our error
+/// format.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(Error))]
+pub struct Query<T>(pub T);
@@ -1,11 +1,10 @@
 use axum::{
     body::Body,
-    extract::{Query, State},
-    http::{Response, StatusCode},
+    extract::State,
+    http::{header, HeaderMap, Response, StatusCode},
     response::IntoResponse,
-    Json,
 };
-use chrono::{DateTime, Local, NaiveTime};
+use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -13,11 +12,26 @@ use crate::{
     app::AppData,
     blocktype::{BlockType, NewBlockType, PushNew},
     currentblock::CurrentBlock,
-    err::{Error, ErrorType},
-    err_from_type, err_with_context,
-    timeblock::{AdjustTimeBlockQuery, SplitTimeBlockQuery, TimeBlock},
+    err::Error,
+    err_with_context,
+    extract::{Json, Query},
+    health, revision,
+    timeblock::{
+        AdjustTimeBlockByIdQuery, AdjustTimeBlockQuery, DeleteTimeBlockQuery, InsertTimeBlockQuery,
+        MergeTimeBlocksQuery, MultiSplitTimeBlockQuery, SplitTimeBlockByIdQuery,
+        SplitTimeBlockQuery, TimeBlock, UpdateTimeBlockQuery,
+    },
+    validate,
 };
 
+/// The `If-Match` header of a mutation request. A header that is not valid
+/// text can never match and is kept as an empty tag.
+fn if_match(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::IF_MATCH)
+        .map(|value| value.to_str().unwrap_or_default())
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct EntireState {
     blocktypes: Vec<BlockType>,
@@ -26,10 +40,11 @@ pub struct EntireState {
 }
 
 pub async fn get_entire_state(State(data): State<AppData>) -> Result<impl IntoResponse, Error> {
-    println!("Getting home state for today");
-    let blocktypes = BlockType::load(&data.data_dir).await?;
-    let daydata = TimeBlock::get_day_timeblocks(&data.data_dir, Local::now().date_naive()).await?;
-    let currentblock = CurrentBlock::get(&data.data_dir).await?;
+    tracing::debug!("Getting home state for today");
+    let blocktypes = BlockType::load(&*data.storage).await?;
+    let daydata = TimeBlock::get_day_timeblocks(&*data.storage, Local::now().date_naive()).await?;
+    let currentblock = CurrentBlock::get(&*data.storage).await?;
+    let etag = revision::of(&daydata)?;
     let entire_state = EntireState {
         blocktypes,
         daydata,
@@ -41,13 +56,14 @@ pub async fn get_entire_state(State(data): State<AppData>) -> Result<impl IntoRe
     Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/json")
+        .header(header::ETAG, etag)
         .body(Body::from(response_body))
         .map_err(|e| err_with_context!(e, "Building response for entire state"))
 }
 
 pub async fn get_blocktypes(State(data): State<AppData>) -> Result<impl IntoResponse, Error> {
-    println!("Getting block types");
-    let blocktypes = BlockType::load(&data.data_dir).await?;
+    tracing::debug!("Getting block types");
+    let blocktypes = BlockType::load(&*data.storage).await?;
     let response_body = serde_json::to_string(&blocktypes)
         .map_err(|e| err_with_context!(e, "Serializing block types"))?;
     Response::builder()
@@ -61,21 +77,14 @@ pub async fn new_blocktype(
     State(data): State<AppData>,
     Json(blocktype): Json<NewBlockType>,
 ) -> Result<impl IntoResponse, Error> {
-    match BlockType::load(&data.data_dir).await {
-        Ok(mut current_blocks) => {
-            println!("Saving new block type {:?}", &blocktype);
-            current_blocks.push_new(blocktype);
-            BlockType::save(&data.data_dir, &current_blocks).await?;
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .body(Body::from("Block type saved"))
-                .map_err(|e| err_with_context!(e, "Building response for block types"))?)
-        }
-        Err(e) => Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from(e.to_string()))
-            .map_err(|e| err_with_context!(e, "Building error response for block types")),
-    }
+    let mut current_blocks = BlockType::load(&*data.storage).await?;
+    tracing::info!(?blocktype, "Saving new block type");
+    current_blocks.push_new(blocktype);
+    BlockType::save(&*data.storage, &current_blocks).await?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::from("Block type saved"))
+        .map_err(|e| err_with_context!(e, "Building response for block types"))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -87,8 +96,9 @@ pub async fn get_daydata(
     State(data): State<AppData>,
     Query(day): Query<DayDataQuery>,
 ) -> Result<impl IntoResponse, Error> {
-    println!("Getting day data for {:?}", day.date);
-    let timeblocks = TimeBlock::get_day_timeblocks(&data.data_dir, day.date.date_naive()).await?;
+    tracing::debug!(date = %day.date, "Getting day data");
+    let timeblocks = TimeBlock::get_day_timeblocks(&*data.storage, day.date.date_naive()).await?;
+    let etag = revision::of(&timeblocks)?;
     let response_body = serde_json::to_string(&timeblocks).map_err(|e| {
         err_with_context!(
             e,
@@ -99,6 +109,7 @@ pub async fn get_daydata(
     Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/json")
+        .header(header::ETAG, etag)
         .body(Body::from(response_body))
         .map_err(|e| {
             err_with_context!(
@@ -111,89 +122,186 @@ pub async fn get_daydata(
 
 pub async fn next_timeblock(
     State(data): State<AppData>,
+    headers: HeaderMap,
     Json(new_current_block): Json<CurrentBlock>,
 ) -> Result<impl IntoResponse, Error> {
-    let time_blocks =
-        TimeBlock::get_day_timeblocks(&data.data_dir, Local::now().date_naive()).await?;
-    let current_data = CurrentBlock::get(&data.data_dir).await?;
-    let time_blocks = if time_blocks.is_empty() {
-        // Get previous day
-        TimeBlock::get_day_timeblocks(
-            &data.data_dir,
-            Local::now().date_naive() - chrono::Duration::days(1),
-        )
-        .await?
-    } else {
-        time_blocks
-    };
-    let start_time = if time_blocks.is_empty() {
-        Local::now()
-            .with_time(NaiveTime::from_hms_opt(0, 0, 0).ok_or(err_from_type!(
-                ErrorType::Chrono,
-                "Creating start time for {}",
-                Local::now().format("%Y-%m-%d")
-            ))?)
-            .single()
-            .ok_or(err_from_type!(
-                ErrorType::Chrono,
-                "No single time identifiable for start time for {}",
-                Local::now().format("%Y-%m-%d")
-            ))?
-    } else {
-        time_blocks
-            .last()
-            .ok_or(err_from_type!(
-                ErrorType::InternalRustError,
-                "This should never happen"
-            ))?
-            .end_time
-    };
-    let end_time = Local::now();
-    let timeblock = TimeBlock::new(
-        start_time,
-        end_time,
-        current_data.block_type_id,
-        current_data.current_block_name,
-    );
-    timeblock.save(&data.data_dir).await?;
-    new_current_block.save(&data.data_dir).await?;
-
+    tracing::info!(?new_current_block, "Starting next timeblock");
+    let etag = TimeBlock::next_timeblock(
+        &*data.storage,
+        &data.locks,
+        Local::now(),
+        new_current_block,
+        if_match(&headers),
+    )
+    .await?;
     Response::builder()
         .status(StatusCode::OK)
+        .header(header::ETAG, etag)
         .body(Body::from("Time block saved"))
         .map_err(|e| err_with_context!(e, "Building response next timeblock"))
 }
 
 pub async fn split_timeblock(
     State(data): State<AppData>,
+    headers: HeaderMap,
     Json(split_time_block_query): Json<SplitTimeBlockQuery>,
 ) -> Result<impl IntoResponse, Error> {
-    println!("Splitting timeblock for {:?}", split_time_block_query);
-    TimeBlock::split_timeblock(&data.data_dir, split_time_block_query).await?;
+    tracing::info!(query = ?split_time_block_query, "Splitting timeblock");
+    let etag = TimeBlock::split_timeblock(
+        &*data.storage,
+        &data.locks,
+        split_time_block_query,
+        if_match(&headers),
+    )
+    .await?;
     Response::builder()
         .status(StatusCode::OK)
+        .header(header::ETAG, etag)
         .body(Body::from("Time block split"))
         .map_err(|e| err_with_context!(e, "Building response split timeblock"))
 }
 
+pub async fn split_timeblock_multi(
+    State(data): State<AppData>,
+    headers: HeaderMap,
+    Json(query): Json<MultiSplitTimeBlockQuery>,
+) -> Result<impl IntoResponse, Error> {
+    tracing::info!(?query, "Splitting timeblock");
+    let etag =
+        TimeBlock::split_timeblock_multi(&*data.storage, &data.locks, query, if_match(&headers))
+            .await?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ETAG, etag)
+        .body(Body::from("Time block split"))
+        .map_err(|e| err_with_context!(e, "Building response split timeblock multi"))
+}
+
 pub async fn adjust_timeblock(
     State(data): State<AppData>,
+    headers: HeaderMap,
     Json(adjust_time_block_query): Json<AdjustTimeBlockQuery>,
 ) -> Result<impl IntoResponse, Error> {
-    println!("Adjusting timeblock for {:?}", adjust_time_block_query);
-    TimeBlock::adjust_timeblock(&data.data_dir, adjust_time_block_query).await?;
+    tracing::info!(query = ?adjust_time_block_query, "Adjusting timeblock");
+    let etag = TimeBlock::adjust_timeblock(
+        &*data.storage,
+        &data.locks,
+        adjust_time_block_query,
+        if_match(&headers),
+    )
+    .await?;
     Response::builder()
         .status(StatusCode::OK)
+        .header(header::ETAG, etag)
         .body(Body::from("Time block adjusted"))
         .map_err(|e| err_with_context!(e, "Building response adjust timeblock"))
 }
 
+pub async fn split_timeblock_by_id(
+    State(data): State<AppData>,
+    headers: HeaderMap,
+    Json(query): Json<SplitTimeBlockByIdQuery>,
+) -> Result<impl IntoResponse, Error> {
+    tracing::info!(?query, "Splitting timeblock");
+    let etag =
+        TimeBlock::split_timeblock_by_id(&*data.storage, &data.locks, query, if_match(&headers))
+            .await?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ETAG, etag)
+        .body(Body::from("Time block split"))
+        .map_err(|e| err_with_context!(e, "Building response split timeblock by id"))
+}
+
+pub async fn adjust_timeblock_by_id(
+    State(data): State<AppData>,
+    headers: HeaderMap,
+    Json(query): Json<AdjustTimeBlockByIdQuery>,
+) -> Result<impl IntoResponse, Error> {
+    tracing::info!(?query, "Adjusting timeblock");
+    let etag =
+        TimeBlock::adjust_timeblock_by_id(&*data.storage, &data.locks, query, if_match(&headers))
+            .await?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ETAG, etag)
+        .body(Body::from("Time block adjusted"))
+        .map_err(|e| err_with_context!(e, "Building response adjust timeblock by id"))
+}
+
+pub async fn update_timeblock(
+    State(data): State<AppData>,
+    headers: HeaderMap,
+    Json(query): Json<UpdateTimeBlockQuery>,
+) -> Result<impl IntoResponse, Error> {
+    tracing::info!(?query, "Updating timeblock");
+    let etag =
+        TimeBlock::update_timeblock(&*data.storage, &data.locks, query, if_match(&headers)).await?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ETAG, etag)
+        .body(Body::from("Time block updated"))
+        .map_err(|e| err_with_context!(e, "Building response update timeblock"))
+}
+
+pub async fn delete_timeblock(
+    State(data): State<AppData>,
+    headers: HeaderMap,
+    Json(query): Json<DeleteTimeBlockQuery>,
+) -> Result<impl IntoResponse, Error> {
+    tracing::info!(?query, "Deleting timeblock");
+    let etag =
+        TimeBlock::delete_timeblock(&*data.storage, &data.locks, query, if_match(&headers)).await?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ETAG, etag)
+        .body(Body::from("Time block deleted"))
+        .map_err(|e| err_with_context!(e, "Building response delete timeblock"))
+}
+
+pub async fn insert_timeblock(
+    State(data): State<AppData>,
+    headers: HeaderMap,
+    Json(query): Json<InsertTimeBlockQuery>,
+) -> Result<impl IntoResponse, Error> {
+    tracing::info!(?query, "Inserting timeblock");
+    let etag = TimeBlock::insert_timeblock(
+        &*data.storage,
+        &data.locks,
+        Local::now(),
+        query,
+        if_match(&headers),
+    )
+    .await?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ETAG, etag)
+        .body(Body::from("Time block inserted"))
+        .map_err(|e| err_with_context!(e, "Building response insert timeblock"))
+}
+
+pub async fn merge_timeblocks(
+    State(data): State<AppData>,
+    headers: HeaderMap,
+    Json(query): Json<MergeTimeBlocksQuery>,
+) -> Result<impl IntoResponse, Error> {
+    tracing::info!(?query, "Merging timeblocks");
+    let etag =
+        TimeBlock::merge_timeblocks(&*data.storage, &data.locks, query, if_match(&headers)).await?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ETAG, etag)
+        .body(Body::from("Time blocks merged"))
+        .map_err(|e| err_with_context!(e, "Building response merge timeblocks"))
+}
+
 pub async fn change_current_block(
     State(data): State<AppData>,
     Json(current_block): Json<CurrentBlock>,
 ) -> Result<impl IntoResponse, Error> {
-    println!("Changing current block to {:?}", current_block);
-    current_block.save(&data.data_dir).await?;
+    tracing::info!(?current_block, "Changing current block");
+    current_block.check(&BlockType::load(&*data.storage).await?)?;
+    current_block.save(&*data.storage).await?;
     Response::builder()
         .status(StatusCode::OK)
         .body(Body::from("Current block saved"))
@@ -201,8 +309,8 @@ pub async fn change_current_block(
 }
 
 pub async fn get_current_block(State(data): State<AppData>) -> Result<impl IntoResponse, Error> {
-    println!("Getting current data");
-    let current_block = CurrentBlock::get(&data.data_dir).await?;
+    tracing::debug!("Getting current block");
+    let current_block = CurrentBlock::get(&*data.storage).await?;
     let response_body = serde_json::to_string(&current_block)
         .map_err(|e| err_with_context!(e, "Serializing current block"))?;
     Response::builder()
@@ -216,11 +324,8 @@ pub async fn get_analysis(
     State(data): State<AppData>,
     Query(query): Query<AnalysisQuery>,
 ) -> Result<impl IntoResponse, Error> {
-    println!(
-        "Getting analysis data from {:?} to {:?}",
-        query.start, query.end
-    );
-    let analysis = Analysis::get_analysis_data(&data.data_dir, query.start, query.end).await?;
+    tracing::debug!(start = %query.start, end = %query.end, "Getting analysis data");
+    let analysis = Analysis::get_analysis_data(&*data.storage, query.start, query.end).await?;
     let response_body = serde_json::to_string(&analysis)
         .map_err(|e| err_with_context!(e, "Serializing analysis data"))?;
     Response::builder()
@@ -229,3 +334,53 @@ pub async fn get_analysis(
         .body(Body::from(response_body))
         .map_err(|e| err_with_context!(e, "Building response for analysis data"))
 }
+
+async fn validation_report(data: AppData, fix: bool) -> Result<Response<Body>, Error> {
+    let reports = validate::validate(&*data.storage, &data.locks, fix).await?;
+    let response_body = serde_json::to_string(&reports)
+        .map_err(|e| err_with_context!(e, "Serializing validation report"))?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(response_body))
+        .map_err(|e| err_with_context!(e, "Building response for validation report"))
+}
+
+pub async fn validate_timeline(State(data): State<AppData>) -> Result<impl IntoResponse, Error> {
+    tracing::info!("Validating timeline");
+    validation_report(data, false).await
+}
+
+pub async fn repair_timeline(State(data): State<AppData>) -> Result<impl IntoResponse, Error> {
+    tracing::info!("Repairing timeline");
+    validation_report(data, true).await
+}
+
+pub async fn get_metrics(State(data): State<AppData>) -> Result<impl IntoResponse, Error> {
+    let response_body = data.metrics.render(&*data.storage).await?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(response_body))
+        .map_err(|e| err_with_context!(e, "Building response for metrics"))
+}
+
+pub async fn healthz() -> impl IntoResponse {
+    "OK"
+}
+
+pub async fn readyz(State(data): State<AppData>) -> Result<impl IntoResponse, Error> {
+    let readiness = health::readiness(&data).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let response_body = serde_json::to_string(&readiness)
+        .map_err(|e| err_with_context!(e, "Serializing readiness"))?;
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(response_body))
+        .map_err(|e| err_with_context!(e, "Building response for readiness"))
+}
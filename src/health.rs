@@ -0,0 +1,118 @@
+use std::path::Path;
+
+use chrono::Local;
+use serde::Serialize;
+
+use crate::{app::AppData, err::Error, err_with_context};
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, res: Result<(), Error>) -> Self {
+        let error = res.err().map(|e| {
+            tracing::warn!(check = name, error = %e, "Readiness check failed");
+            e.error_type.to_string()
+        });
+        Check {
+            name,
+            ok: error.is_none(),
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+/// Start of the name of the probe files `check_writable` creates.
+pub const PROBE_PREFIX: &str = ".readyz.";
+
+/// Create and remove a probe file in `data_dir`, named uniquely so
+/// concurrent probes never touch each other's file.
+async fn check_writable(data_dir: &Path) -> Result<(), Error> {
+    let probe = data_dir.join(format!("{}{:016x}", PROBE_PREFIX, rand::random::<u64>()));
+    tokio::fs::write(&probe, b"")
+        .await
+        .map_err(|e| err_with_context!(e, "Writing {}", probe.display()))?;
+    tokio::fs::remove_file(&probe)
+        .await
+        .map_err(|e| err_with_context!(e, "Removing {}", probe.display()))
+}
+
+/// Whether the server can serve requests: the data directory takes writes and
+/// the block types and today's time blocks can be read.
+pub async fn readiness(data: &AppData) -> Readiness {
+    let today = Local::now().date_naive();
+    let checks = vec![
+        Check::new("data_dir_writable", check_writable(&data.data_dir).await),
+        Check::new(
+            "blocktypes_readable",
+            data.storage.load_blocktypes().await.map(|_| ()),
+        ),
+        Check::new(
+            "today_readable",
+            data.storage.get_day_timeblocks(today).await.map(|_| ()),
+        ),
+    ];
+    Readiness {
+        ready: checks.iter().all(|c| c.ok),
+        checks,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use axum::{extract::State, http::StatusCode, response::IntoResponse};
+
+    use super::*;
+    use crate::{handlers, storage::StorageKind, testing::check};
+
+    async fn status(data: &AppData) -> StatusCode {
+        match handlers::readyz(State(data.clone())).await {
+            Ok(response) => response.into_response().status(),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn health_and_concurrent_readiness_probes() {
+        assert_eq!(
+            handlers::healthz().await.into_response().status(),
+            StatusCode::OK
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let data = check(AppData::init(dir.path().to_path_buf(), StorageKind::Json).await);
+        let mut tasks = Vec::new();
+        for _ in 0..32 {
+            let data = data.clone();
+            tasks.push(tokio::spawn(async move { readiness(&data).await.ready }));
+        }
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+        assert_eq!(status(&data).await, StatusCode::OK);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        std::fs::write(dir.path().join("blocktypes.json"), "{").unwrap();
+        assert_eq!(status(&data).await, StatusCode::SERVICE_UNAVAILABLE);
+        let readiness = readiness(&data).await;
+        let failed = readiness
+            .checks
+            .iter()
+            .filter(|c| !c.ok)
+            .map(|c| c.name)
+            .collect::<Vec<_>>();
+        assert_eq!(failed, ["blocktypes_readable"]);
+    }
+}
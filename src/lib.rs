@@ -1,32 +1,88 @@
 #![deny(clippy::unwrap_used, clippy::expect_used)]
 
 use app::{AppData, AppState};
+use auth::{keys::SigningKeys, password::Password, sessions::Sessions};
 use axum::{
     middleware::from_fn_with_state,
     routing::{get, post},
     Extension, Router,
 };
-use std::path::PathBuf;
-use tokio::net::TcpListener;
+use err::ErrorType;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 mod analysis;
 mod app;
+mod atomicfile;
 mod auth;
 mod blocktype;
+mod config;
 mod currentblock;
+mod datalock;
 mod err;
+mod extract;
 mod handlers;
+mod health;
+mod listen;
+mod locks;
+mod logging;
+mod metrics;
+mod migrate;
+mod revision;
+mod shutdown;
+mod storage;
+#[cfg(test)]
+mod testing;
 mod timeblock;
+mod tls;
+mod validate;
+mod violations;
 
-pub async fn run(
-    port: u16,
-    password_hash: String,
-    data_dir: PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ip = format!("0.0.0.0:{}", port);
-    let state = AppState::init(password_hash).await;
-    let data = AppData::init(data_dir).await;
+pub use config::{Config, TlsConfig, CONFIG_ENV};
+pub use logging::{init as init_logging, LogFormat};
+pub use migrate::MigrateOptions;
+pub use storage::StorageKind;
+
+pub async fn run(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let data_dir = config.data_dir.ok_or("No data directory configured")?;
+    let _data_dir_lock = datalock::DataDirLock::acquire(&data_dir).map_err(|e| e.to_string())?;
+    let cors = config.cors.layer()?;
+    if config.tls.is_none() && config.bind.iter().any(|ip| !ip.is_loopback()) {
+        tracing::warn!(
+            "Serving plain HTTP beyond localhost, passwords and tokens travel unencrypted"
+        );
+    }
+    atomicfile::recover_data_dir(&data_dir)
+        .await
+        .map_err(|e| e.to_string())?;
+    migrate::check_version(&data_dir)
+        .await
+        .map_err(|e| e.to_string())?;
+    let listeners =
+        listen::Listener::bind_all(&config.bind, config.port, config.unix_socket.as_deref())
+            .await?;
+    let metrics_listeners = match config.metrics.bind {
+        Some(addr) => listen::Listener::bind_tcp(&[addr.ip()], addr.port()).await?,
+        None => Vec::new(),
+    };
+    let data = AppData::init(data_dir.clone(), config.storage)
+        .await
+        .map_err(|e| e.to_string())?;
+    let password = Password::load(&data_dir).await.map_err(|e| e.to_string())?;
+    let keys = SigningKeys::load_or_create(&data_dir)
+        .await
+        .map_err(|e| e.to_string())?;
+    let sessions = Sessions::load(&data_dir).await.map_err(|e| e.to_string())?;
+    let state = AppState::init(password, keys, sessions, data.metrics.clone(), config.auth).await;
+    let storage = data.storage.clone();
+    let writes = shutdown::Writes::default();
 
+    // For scrapers that cannot log in, on an address of its own.
+    let metrics_routes = Router::new()
+        .route("/metrics", get(handlers::get_metrics))
+        .with_state(data.clone());
     let routes = Router::new()
         // Main home state for today
         .route("/state", get(handlers::get_entire_state))
@@ -38,11 +94,38 @@ pub async fn run(
         .route("/timeblock/next", post(handlers::next_timeblock))
         .route("/timeblock/split", post(handlers::split_timeblock))
         .route("/timeblock/adjust", post(handlers::adjust_timeblock))
+        .route("/timeblock/split/id", post(handlers::split_timeblock_by_id))
+        .route(
+            "/timeblock/split/multi",
+            post(handlers::split_timeblock_multi),
+        )
+        .route(
+            "/timeblock/adjust/id",
+            post(handlers::adjust_timeblock_by_id),
+        )
+        .route("/timeblock/update", post(handlers::update_timeblock))
+        .route("/timeblock/delete", post(handlers::delete_timeblock))
+        .route("/timeblock/merge", post(handlers::merge_timeblocks))
+        .route("/timeblock/insert", post(handlers::insert_timeblock))
         // Current block
         .route("/currentblock/get", get(handlers::get_current_block))
         .route("/currentblock/change", post(handlers::change_current_block))
         // Analysis
         .route("/analysis", get(handlers::get_analysis))
+        .route("/metrics", get(handlers::get_metrics))
+        // Admin
+        .route(
+            "/admin/validate",
+            get(handlers::validate_timeline).post(handlers::repair_timeline),
+        )
+        .route("/admin/rotate-key", post(auth::handlers::rotate_key))
+        // Sessions
+        .route("/auth/logout", post(auth::handlers::logout))
+        .route("/auth/sessions", get(auth::handlers::list_sessions))
+        .route(
+            "/auth/sessions/revoke",
+            post(auth::handlers::revoke_session),
+        )
         .layer(from_fn_with_state(
             state.clone(),
             auth::middleware::auth_middleware,
@@ -51,10 +134,161 @@ pub async fn run(
         .route("/auth/login", post(auth::handlers::login))
         .route("/auth/refresh", post(auth::handlers::refresh_token))
         .route("/auth/check", post(auth::handlers::check_token))
+        // Health
+        .route("/healthz", get(handlers::healthz))
+        .route("/readyz", get(handlers::readyz))
+        .layer(from_fn_with_state(
+            data.metrics.clone(),
+            metrics::track_requests,
+        ))
+        .layer(from_fn_with_state(
+            writes.clone(),
+            shutdown::complete_writes,
+        ))
         .layer(Extension(state.clone()))
         .with_state(data);
+    let routes = match cors {
+        Some(cors) => routes.layer(cors),
+        None => routes,
+    };
+    let routes = logging::trace_requests(routes);
+
+    let mut servers = listen::Servers::default();
+    match &config.tls {
+        Some(tls_config) => {
+            let rustls = tls::load(tls_config).await?;
+            for listener in listeners {
+                servers.tls(listener, routes.clone(), rustls.clone())?;
+            }
+            if let Some(redirect_port) = tls_config.http_redirect_port {
+                let https_port = config.port.ok_or("Redirecting to HTTPS needs a port")?;
+                for listener in listen::Listener::bind_tcp(&config.bind, redirect_port).await? {
+                    servers.plain(listener, tls::redirect_routes(https_port));
+                }
+            }
+        }
+        None => {
+            for listener in listeners {
+                servers.plain(listener, routes.clone());
+            }
+        }
+    }
+    for listener in metrics_listeners {
+        servers.plain(listener, metrics_routes.clone());
+    }
+    let drain_timeout = Duration::from_secs(u64::from(config.shutdown_timeout_secs));
+    servers.run(shutdown::signal(), drain_timeout).await?;
+
+    tracing::info!("Waiting for writes to finish");
+    let _no_more_writes = writes.finish().await;
+    storage.flush().await.map_err(|e| e.to_string())?;
+    tracing::info!("Stopped");
+    Ok(())
+}
+
+/// Hash `password` into `password.txt` in `data_dir`.
+pub async fn set_password(
+    data_dir: &Path,
+    password: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Password::set(data_dir, password)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Replace the JWT signing key in `data_dir`. Tokens signed by the old one
+/// keep working for `grace_secs`. Refuses while a server uses `data_dir`,
+/// which would not notice the new key; use `/admin/rotate-key` then.
+pub async fn rotate_key(
+    data_dir: PathBuf,
+    grace_secs: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _data_dir_lock = datalock::DataDirLock::acquire(&data_dir).map_err(|e| {
+        format!(
+            "{}\nUse POST /admin/rotate-key on the running server instead",
+            e
+        )
+    })?;
+    let keys = SigningKeys::load_or_create(&data_dir)
+        .await
+        .map_err(|e| e.to_string())?;
+    keys.rotate(chrono::Duration::seconds(i64::from(grace_secs)))
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub async fn migrate(
+    data_dir: PathBuf,
+    options: MigrateOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _data_dir_lock = datalock::DataDirLock::acquire(&data_dir).map_err(|e| e.to_string())?;
+    migrate::migrate(&data_dir, options)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Report timeline anomalies in the data directory, repairing what can be
+/// repaired when `fix` is set. Only reports while a server uses `data_dir`,
+/// whose writes a repair would race; use `/admin/validate` then.
+pub async fn validate(
+    data_dir: PathBuf,
+    storage_kind: StorageKind,
+    fix: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let data_dir_lock = match datalock::DataDirLock::acquire(&data_dir) {
+        Ok(lock) => Some(lock),
+        Err(e) if matches!(e.error_type, ErrorType::DataDirInUse) => {
+            if fix {
+                return Err(format!(
+                    "{}\nUse POST /admin/validate on the running server instead",
+                    e
+                )
+                .into());
+            }
+            None
+        }
+        Err(e) => return Err(e.to_string().into()),
+    };
+    // The server recovered the directory when it started and may be writing
+    // to it now.
+    if data_dir_lock.is_some() {
+        atomicfile::recover_data_dir(&data_dir)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    migrate::check_version(&data_dir)
+        .await
+        .map_err(|e| e.to_string())?;
+    let data = AppData::init(data_dir, storage_kind)
+        .await
+        .map_err(|e| e.to_string())?;
+    let reports = validate::validate(&*data.storage, &data.locks, fix)
+        .await
+        .map_err(|e| e.to_string())?;
 
-    let listener = TcpListener::bind(&ip).await?;
-    axum::serve(listener, routes).await?;
+    if reports.is_empty() {
+        println!("No anomalies found");
+    }
+    for report in &reports {
+        println!(
+            "{}{}",
+            report.day,
+            if report.fixed { " (fixed)" } else { "" }
+        );
+        for anomaly in &report.anomalies {
+            println!("  {}", anomaly);
+        }
+        if report.fixed {
+            for repair in &report.repairs {
+                println!("  fixed: {}", repair);
+            }
+            for anomaly in &report.remaining {
+                println!("  needs review: {}", anomaly);
+            }
+        }
+    }
     Ok(())
 }
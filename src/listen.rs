@@ -0,0 +1,331 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use tokio::{net::TcpListener, sync::watch, task::JoinSet};
+
+/// A socket the server accepts connections on.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Bind every address in `addrs` on `port`, and `unix_socket` if given.
+    /// Everything is bound before anything is served so a taken port fails
+    /// the start instead of leaving the server half up.
+    pub async fn bind_all(
+        addrs: &[IpAddr],
+        port: Option<u16>,
+        unix_socket: Option<&Path>,
+    ) -> Result<Vec<Listener>, Box<dyn std::error::Error>> {
+        if addrs.is_empty() && unix_socket.is_none() {
+            return Err("Nothing to listen on, set a bind address or a unix socket".into());
+        }
+        let mut listeners = Vec::new();
+        if !addrs.is_empty() {
+            let port = port.ok_or("No port configured")?;
+            listeners.extend(Listener::bind_tcp(addrs, port).await?);
+        }
+        if let Some(path) = unix_socket {
+            listeners.push(Listener::bind_unix(path)?);
+        }
+        Ok(listeners)
+    }
+
+    pub async fn bind_tcp(
+        addrs: &[IpAddr],
+        port: u16,
+    ) -> Result<Vec<Listener>, Box<dyn std::error::Error>> {
+        let mut listeners = Vec::new();
+        for ip in addrs {
+            let addr = SocketAddr::new(*ip, port);
+            let listener =
+                Listener::bind_addr(addr).map_err(|e| format!("Binding {}: {}", addr, e))?;
+            listeners.push(Listener::Tcp(listener));
+        }
+        Ok(listeners)
+    }
+
+    /// Bind like `TcpListener::bind`, except that IPv6 sockets only accept
+    /// IPv6, so `::` and `0.0.0.0` can be bound side by side.
+    fn bind_addr(addr: SocketAddr) -> io::Result<TcpListener> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Bind a unix socket at `path`, replacing a socket left behind by an
+    /// earlier run. Any other file at `path` is left alone.
+    #[cfg(unix)]
+    fn bind_unix(path: &Path) -> Result<Listener, Box<dyn std::error::Error>> {
+        use std::os::unix::fs::FileTypeExt;
+
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(format!("{} exists and is not a socket", path.display()).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .map_err(|e| format!("Binding {}: {}", path.display(), e))?;
+        Ok(Listener::Unix(listener, path.to_path_buf()))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_path: &Path) -> Result<Listener, Box<dyn std::error::Error>> {
+        Err("Unix sockets are not supported on this platform".into())
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "tcp".to_string(),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+/// Servers running on their own tasks.
+pub struct Servers {
+    tasks: JoinSet<io::Result<()>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Default for Servers {
+    fn default() -> Self {
+        Servers {
+            tasks: JoinSet::new(),
+            shutdown: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Servers {
+    /// Resolves once shutdown starts.
+    fn stopping(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut shutdown = self.shutdown.subscribe();
+        async move {
+            // An error means `self` is gone, which is as good as stopping.
+            let _ = shutdown.wait_for(|stopping| *stopping).await;
+        }
+    }
+
+    /// Serve `routes` over plain HTTP on `listener`.
+    pub fn plain(&mut self, listener: Listener, routes: Router) {
+        tracing::info!(address = %listener.describe(), "Listening");
+        let stopping = self.stopping();
+        match listener {
+            Listener::Tcp(listener) => {
+                self.tasks.spawn(async move {
+                    axum::serve(listener, routes)
+                        .with_graceful_shutdown(stopping)
+                        .await
+                });
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                self.tasks.spawn(async move {
+                    axum::serve(listener, routes)
+                        .with_graceful_shutdown(stopping)
+                        .await
+                });
+            }
+        }
+    }
+
+    /// Serve `routes` over HTTPS on `listener`. Unix sockets are served
+    /// plain, TLS is left to whatever proxy connects to them.
+    pub fn tls(&mut self, listener: Listener, routes: Router, tls: RustlsConfig) -> io::Result<()> {
+        let listener = match listener {
+            Listener::Tcp(listener) => listener,
+            #[cfg(unix)]
+            unix @ Listener::Unix(..) => {
+                self.plain(unix, routes);
+                return Ok(());
+            }
+        };
+        let address = listener.local_addr()?;
+        tracing::info!(address = %address, "Listening with TLS");
+        let handle = Handle::new();
+        let server =
+            axum_server::from_tcp_rustls(listener.into_std()?, tls)?.handle(handle.clone());
+        let stopping = self.stopping();
+        self.tasks.spawn(async move {
+            tokio::spawn(async move {
+                stopping.await;
+                handle.graceful_shutdown(None);
+            });
+            server.serve(routes.into_make_service()).await
+        });
+        Ok(())
+    }
+
+    /// Run until one of the servers fails or `signal` resolves. Then stop
+    /// accepting connections and give requests still running `drain_timeout`
+    /// to finish before dropping them.
+    pub async fn run(
+        mut self,
+        signal: impl Future<Output = ()>,
+        drain_timeout: Duration,
+    ) -> io::Result<()> {
+        tokio::select! {
+            res = join_all(&mut self.tasks) => return res,
+            () = signal => {}
+        }
+        tracing::info!(
+            timeout_secs = drain_timeout.as_secs(),
+            "Shutting down, draining requests"
+        );
+        self.shutdown.send_replace(true);
+        match tokio::time::timeout(drain_timeout, join_all(&mut self.tasks)).await {
+            Ok(res) => res,
+            Err(_) => {
+                tracing::warn!(
+                    servers = self.tasks.len(),
+                    "Drain timeout passed, dropping remaining connections"
+                );
+                self.tasks.shutdown().await;
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn join_all(tasks: &mut JoinSet<io::Result<()>>) -> io::Result<()> {
+    while let Some(res) = tasks.join_next().await {
+        res.map_err(io::Error::other)??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use axum::routing::get;
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        sync::oneshot,
+    };
+
+    use super::*;
+
+    async fn get_root(mut stream: impl AsyncRead + AsyncWrite + Unpin) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn every_address_and_the_unix_socket_are_served() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("server.sock");
+        // A socket left behind by an earlier run.
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let listeners = Listener::bind_all(&[localhost, localhost], Some(0), Some(&socket))
+            .await
+            .unwrap();
+        assert_eq!(listeners.len(), 3);
+        let addrs = listeners
+            .iter()
+            .filter_map(|listener| match listener {
+                Listener::Tcp(listener) => Some(listener.local_addr().unwrap()),
+                Listener::Unix(..) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(addrs.len(), 2);
+
+        let routes = Router::new().route("/", get(|| async { "served" }));
+        let mut servers = Servers::default();
+        for listener in listeners {
+            servers.plain(listener, routes.clone());
+        }
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(servers.run(
+            async {
+                let _ = stopped.await;
+            },
+            Duration::from_secs(5),
+        ));
+
+        for addr in addrs {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            assert!(get_root(stream).await.ends_with("served"));
+        }
+        let stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        assert!(get_root(stream).await.ends_with("served"));
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn both_wildcards_bind_side_by_side() {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let wildcards = [
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        ];
+        let listeners = Listener::bind_tcp(&wildcards, port).await.unwrap();
+        assert_eq!(listeners.len(), 2);
+        // The IPv4 connection reaches the IPv4 listener only.
+        tokio::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        let Listener::Tcp(v4) = &listeners[0] else {
+            panic!("expected a TCP listener");
+        };
+        v4.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn binding_fails_as_a_whole() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(Listener::bind_all(&[], None, None).await.is_err());
+        assert!(Listener::bind_all(&[localhost], None, None).await.is_err());
+
+        let taken = TcpListener::bind((localhost, 0)).await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert!(Listener::bind_tcp(&[localhost], port).await.is_err());
+
+        // Only sockets are replaced, never other files.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.sock");
+        std::fs::write(&path, "data").unwrap();
+        assert!(Listener::bind_all(&[], None, Some(&path)).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::NaiveDate;
+use tokio::sync::OwnedMutexGuard;
+
+use crate::{
+    err::{Error, ErrorType},
+    err_from_type,
+};
+
+/// Hands out one async lock per day so read-modify-write cycles on a day's
+/// time blocks never interleave.
+#[derive(Debug, Default, Clone)]
+pub struct DayLocks {
+    locks: Arc<Mutex<HashMap<NaiveDate, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+/// Holds the locks of every day passed to `DayLocks::lock` until dropped.
+#[must_use]
+pub struct DayGuard {
+    _guards: Vec<OwnedMutexGuard<()>>,
+}
+
+impl DayLocks {
+    /// Lock all of `days`. Locks are always taken in date order so callers
+    /// locking overlapping sets of days cannot deadlock.
+    pub async fn lock(&self, days: &[NaiveDate]) -> Result<DayGuard, Error> {
+        let mut days = days.to_vec();
+        days.sort();
+        days.dedup();
+
+        let day_locks = {
+            let mut locks = self.locks.lock().map_err(|_| {
+                err_from_type!(ErrorType::InternalRustError, "Day lock table poisoned")
+            })?;
+            // Forget days nobody is holding or waiting on.
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            days.iter()
+                .map(|day| locks.entry(*day).or_default().clone())
+                .collect::<Vec<_>>()
+        };
+
+        let mut guards = Vec::with_capacity(day_locks.len());
+        for lock in day_locks {
+            guards.push(lock.lock_owned().await);
+        }
+        Ok(DayGuard { _guards: guards })
+    }
+}
@@ -0,0 +1,163 @@
+use std::{env, str::FromStr, time::Duration};
+
+use axum::{
+    body::Body,
+    http::{Request, Response},
+    Router,
+};
+use serde::Deserialize;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{field, Span};
+use tracing_subscriber::EnvFilter;
+
+/// Filter directives used when neither `--log-level` nor the environment set
+/// one.
+const DEFAULT_LEVEL: &str = "info";
+/// Environment variable holding filter directives, in `RUST_LOG` syntax.
+pub const LEVEL_ENV: &str = "TIME_SCHEDULER_LOG";
+/// Environment variable holding the log format.
+pub const FORMAT_ENV: &str = "TIME_SCHEDULER_LOG_FORMAT";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Multi-line human readable output.
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}, expected pretty or json", s)),
+        }
+    }
+}
+
+/// Install the global subscriber. `level` and `format` come from the command
+/// line and win over the environment.
+pub fn init(
+    level: Option<&str>,
+    format: Option<LogFormat>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let level = match level {
+        Some(level) => level.to_string(),
+        None => env::var(LEVEL_ENV).unwrap_or_else(|_| DEFAULT_LEVEL.to_string()),
+    };
+    let format = match format {
+        Some(format) => format,
+        None => match env::var(FORMAT_ENV) {
+            Ok(format) => format.parse()?,
+            Err(_) => LogFormat::default(),
+        },
+    };
+    let filter = EnvFilter::try_new(&level)?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    }
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Give every request an `x-request-id`, echoed in the response, and a span
+/// recording its method, path, status and latency.
+pub fn trace_requests(router: Router) -> Router {
+    router
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request<Body>| {
+                    let request_id = request
+                        .headers()
+                        .get("x-request-id")
+                        .and_then(|id| id.to_str().ok())
+                        .unwrap_or_default();
+                    tracing::info_span!(
+                        "request",
+                        method = %request.method(),
+                        path = %request.uri().path(),
+                        request_id = %request_id,
+                        status = field::Empty,
+                        latency_ms = field::Empty,
+                    )
+                })
+                .on_request(())
+                .on_response(
+                    |response: &Response<Body>, latency: Duration, span: &Span| {
+                        span.record("status", response.status().as_u16());
+                        span.record("latency_ms", latency.as_millis() as u64);
+                        tracing::info!("Request finished");
+                    },
+                )
+                .on_failure(()),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use axum::{http::HeaderMap, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// The id the response carries and the one the handler saw.
+    async fn request_ids(routes: &Router, incoming: Option<&str>) -> (String, String) {
+        let mut request = Request::get("/");
+        if let Some(id) = incoming {
+            request = request.header("x-request-id", id);
+        }
+        let response = routes
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let echoed = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        (echoed, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_generated_or_kept() {
+        let routes = trace_requests(Router::new().route(
+            "/",
+            get(|headers: HeaderMap| async move {
+                headers["x-request-id"].to_str().unwrap().to_string()
+            }),
+        ));
+
+        let (echoed, seen) = request_ids(&routes, None).await;
+        assert!(uuid::Uuid::parse_str(&echoed).is_ok());
+        assert_eq!(seen, echoed);
+        let (other, _) = request_ids(&routes, None).await;
+        assert_ne!(other, echoed);
+
+        let (echoed, seen) = request_ids(&routes, Some("from-the-proxy")).await;
+        assert_eq!(echoed, "from-the-proxy");
+        assert_eq!(seen, "from-the-proxy");
+    }
+
+    #[test]
+    fn formats_parse() {
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("pretty".parse::<LogFormat>(), Ok(LogFormat::Pretty));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,6 +1,8 @@
-use std::{env, path::PathBuf};
+use std::{env, net::IpAddr, path::PathBuf};
 
-use time_scheduler_server as app;
+use time_scheduler_server::{
+    self as app, Config, LogFormat, MigrateOptions, StorageKind, TlsConfig, CONFIG_ENV,
+};
 
 macro_rules! password_input {
     ($($fmt:expr),*) => {
@@ -26,54 +28,256 @@ macro_rules! input {
     };
 }
 
+/// Options given on the command line. Anything set here wins over the config
+/// file and the environment.
+#[derive(Default)]
+struct Cli {
+    config: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    port: Option<u16>,
+    bind: Vec<IpAddr>,
+    unix_socket: Option<PathBuf>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    http_redirect_port: Option<u16>,
+    storage: Option<StorageKind>,
+    log_level: Option<String>,
+    log_format: Option<LogFormat>,
+    non_interactive: bool,
+    shutdown_timeout_secs: Option<u32>,
+    access_token_lifetime_secs: Option<u32>,
+    refresh_token_lifetime_secs: Option<u32>,
+}
+
+impl Cli {
+    fn apply(self, config: &mut Config) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(data_dir) = self.data_dir {
+            config.data_dir = Some(data_dir);
+        }
+        if let Some(port) = self.port {
+            config.port = Some(port);
+        }
+        if !self.bind.is_empty() {
+            config.bind = self.bind;
+        }
+        if let Some(unix_socket) = self.unix_socket {
+            config.unix_socket = Some(unix_socket);
+        }
+        match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => {
+                let http_redirect_port = config.tls.as_ref().and_then(|t| t.http_redirect_port);
+                config.tls = Some(TlsConfig {
+                    cert,
+                    key,
+                    http_redirect_port,
+                });
+            }
+            (None, None) => {}
+            _ => return Err("--tls-cert and --tls-key must be given together".into()),
+        }
+        if let Some(port) = self.http_redirect_port {
+            config
+                .tls
+                .as_mut()
+                .ok_or("--http-redirect-port needs TLS to be configured")?
+                .http_redirect_port = Some(port);
+        }
+        if let Some(storage) = self.storage {
+            config.storage = storage;
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = Some(level);
+        }
+        if let Some(format) = self.log_format {
+            config.log.format = Some(format);
+        }
+        if self.non_interactive {
+            config.non_interactive = true;
+        }
+        if let Some(secs) = self.shutdown_timeout_secs {
+            config.shutdown_timeout_secs = secs;
+        }
+        if let Some(secs) = self.access_token_lifetime_secs {
+            config.auth.access_token_lifetime_secs = secs;
+        }
+        if let Some(secs) = self.refresh_token_lifetime_secs {
+            config.auth.refresh_token_lifetime_secs = secs;
+        }
+        Ok(())
+    }
+}
+
+/// The config file, overridden by the environment, overridden by `cli`.
+fn load_config(mut cli: Cli) -> Result<Config, Box<dyn std::error::Error>> {
+    let config_path = cli
+        .config
+        .take()
+        .or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from));
+    let mut config = Config::load(config_path.as_deref())?;
+    cli.apply(&mut config)?;
+    Ok(config)
+}
+
+/// Parse `--config` and `--data-dir`, which every subcommand takes. Returns
+/// whether `arg` was one of them.
+fn subcommand_option<'a>(
+    cli: &mut Cli,
+    arg: &str,
+    args_iter: &mut impl Iterator<Item = &'a str>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    match arg {
+        "--config" => {
+            let config_str = args_iter.next().ok_or("Missing config file")?;
+            cli.config = Some(PathBuf::from(config_str));
+        }
+        "--data-dir" => {
+            let data_dir_str = args_iter.next().ok_or("Missing data directory")?;
+            cli.data_dir = Some(PathBuf::from(data_dir_str));
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Resolve the configuration for a subcommand like the server does, falling
+/// back to the current directory for the data directory.
+fn subcommand_config(cli: Cli) -> Result<(Config, PathBuf), Box<dyn std::error::Error>> {
+    let mut config = load_config(cli)?;
+    app::init_logging(config.log.level.as_deref(), config.log.format)?;
+    let data_dir = match config.data_dir.take() {
+        Some(data_dir) => data_dir,
+        None => env::current_dir()?,
+    };
+    Ok((config, data_dir))
+}
+
+fn print_help() {
+    println!("Usage: time-scheduler-server [options]");
+    println!("       time-scheduler-server migrate [--config <file>] [--data-dir <data_dir>] [--overwrite] [--dry-run]");
+    println!("       time-scheduler-server validate [--config <file>] [--data-dir <data_dir>] [--storage <json|sqlite>] [--fix]");
+    println!("       time-scheduler-server rotate-key [--config <file>] [--data-dir <data_dir>] [--grace <secs>]");
+    println!("Options:");
+    println!(
+        "  --config <file>          TOML config file (env: {})",
+        CONFIG_ENV
+    );
+    println!("  --data-dir <data_dir>    Data directory");
+    println!("  --port <port>            Port");
+    println!("  --bind <address>         Address to listen on, repeat for more (default: 0.0.0.0)");
+    println!("  --unix-socket <path>     Also listen on a unix domain socket");
+    println!(
+        "  --tls-cert <file>        PEM certificate chain, serves HTTPS together with --tls-key"
+    );
+    println!("  --tls-key <file>         PEM private key");
+    println!("  --http-redirect-port <port>  Redirect plain HTTP on this port to HTTPS");
+    println!("  --storage <json|sqlite>  Storage backend (default: json)");
+    println!("  --log-level <filter>     Log level or filter directives (default: info, env: TIME_SCHEDULER_LOG)");
+    println!("  --log-format <pretty|json>  Log format (default: pretty, env: TIME_SCHEDULER_LOG_FORMAT)");
+    println!("  --non-interactive        Fail instead of prompting for missing settings");
+    println!(
+        "  --shutdown-timeout <secs>  Time given to running requests on shutdown (default: 30)"
+    );
+    println!("  --access-token-lifetime <secs>  Access token lifetime (default: 30)");
+    println!("  --refresh-token-lifetime <secs>  Refresh token lifetime (default: 604800)");
+    println!("  --help                   Show this help message");
+    println!("  --version                Show version");
+    println!();
+    println!("Command line options override the environment, which overrides the config file.");
+    println!("Subcommands read the config file and environment too, and default to the current");
+    println!("directory as data directory.");
+    println!("Unless --non-interactive is given, a missing data directory, port or password is");
+    println!("prompted for.");
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = env::args().collect::<Vec<String>>();
+    let args = env::args().skip(1).collect::<Vec<String>>();
 
-    if args.len() == 1 {
-        println!("Usage: time-scheduler-server --data-dir <data_dir> --port <port> --help");
-        return Ok(());
+    match args.first().map(|s| s.as_str()) {
+        Some("migrate") => return migrate(&args[1..]).await,
+        Some("validate") => return validate(&args[1..]).await,
+        Some("rotate-key") => return rotate_key(&args[1..]).await,
+        _ => {}
     }
 
-    let mut data_dir = None;
-    let mut port = None;
-
+    let mut cli = Cli::default();
     let mut args_iter = args.iter().map(|s| s.as_str());
 
     while let Some(arg) = args_iter.next() {
         match arg {
+            "--config" => {
+                let config_str = args_iter.next().ok_or("Missing config file")?;
+                cli.config = Some(PathBuf::from(config_str));
+            }
             "--data-dir" => {
                 let data_dir_str = args_iter.next().ok_or("Missing data directory")?;
-                println!("Data directory: {}", data_dir_str);
-                data_dir = Some(PathBuf::from(data_dir_str));
+                cli.data_dir = Some(PathBuf::from(data_dir_str));
             }
             "--port" => {
                 let port_str = args_iter.next().ok_or("Missing port")?;
-                println!("Port: {}", port_str);
-                port = Some(port_str.parse()?);
+                cli.port = Some(port_str.parse()?);
+            }
+            "--bind" => {
+                let bind_str = args_iter.next().ok_or("Missing bind address")?;
+                cli.bind.push(bind_str.parse()?);
+            }
+            "--unix-socket" => {
+                let socket_str = args_iter.next().ok_or("Missing unix socket path")?;
+                cli.unix_socket = Some(PathBuf::from(socket_str));
+            }
+            "--tls-cert" => {
+                let cert_str = args_iter.next().ok_or("Missing TLS certificate")?;
+                cli.tls_cert = Some(PathBuf::from(cert_str));
+            }
+            "--tls-key" => {
+                let key_str = args_iter.next().ok_or("Missing TLS key")?;
+                cli.tls_key = Some(PathBuf::from(key_str));
+            }
+            "--http-redirect-port" => {
+                let port_str = args_iter.next().ok_or("Missing redirect port")?;
+                cli.http_redirect_port = Some(port_str.parse()?);
+            }
+            "--storage" => {
+                let storage_str = args_iter.next().ok_or("Missing storage backend")?;
+                cli.storage = Some(storage_str.parse()?);
+            }
+            "--log-level" => {
+                cli.log_level = Some(args_iter.next().ok_or("Missing log level")?.to_string());
+            }
+            "--log-format" => {
+                let format_str = args_iter.next().ok_or("Missing log format")?;
+                cli.log_format = Some(format_str.parse::<LogFormat>()?);
+            }
+            "--non-interactive" => cli.non_interactive = true,
+            "--access-token-lifetime" => {
+                let secs_str = args_iter.next().ok_or("Missing access token lifetime")?;
+                cli.access_token_lifetime_secs = Some(secs_str.parse()?);
+            }
+            "--refresh-token-lifetime" => {
+                let secs_str = args_iter.next().ok_or("Missing refresh token lifetime")?;
+                cli.refresh_token_lifetime_secs = Some(secs_str.parse()?);
+            }
+            "--shutdown-timeout" => {
+                let secs_str = args_iter.next().ok_or("Missing shutdown timeout")?;
+                cli.shutdown_timeout_secs = Some(secs_str.parse()?);
             }
             "--help" => {
-                println!("Usage: time-scheduler-server");
-                println!("Options:");
-                println!("  --data-dir <data_dir>    Data directory");
-                println!("  --port <port>            Port");
-                println!("  --help                   Show this help message");
-                println!("  --version                Show version");
-                println!();
-                println!("If no data directory or port is specified, the user will be prompted to enter it.");
-                println!("If nor password.txt is found in the data directory, the user will be prompted to enter it.");
+                print_help();
                 return Ok(());
             }
             "--version" => {
                 println!("{}: {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
                 return Ok(());
             }
-            _ => {}
+            _ => return Err(format!("Unknown option {}, see --help", arg).into()),
         }
     }
 
-    let data_dir = match data_dir {
+    let mut config = load_config(cli)?;
+
+    let data_dir = match config.data_dir.take() {
         Some(data_dir) => data_dir,
+        None if config.non_interactive => return Err("Data directory not specified".into()),
         None => {
             println!("Data directory not specified");
             let data_dir_str = input!("Enter data directory: ");
@@ -81,24 +285,96 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    let port = match port {
-        Some(port) => port,
-        None => {
-            println!("Port not specified");
-            let port_str = input!("Enter port: ");
-            port_str.parse()?
+    if config.port.is_none() && !config.bind.is_empty() {
+        if config.non_interactive {
+            return Err("Port not specified".into());
         }
-    };
+        println!("Port not specified");
+        let port_str = input!("Enter port: ");
+        config.port = Some(port_str.parse()?);
+    }
 
     let password_path = data_dir.join("password.txt");
-    let password = if password_path.exists() {
-        std::fs::read_to_string(password_path)?.trim().to_string()
-    } else {
+    if !password_path.exists() {
+        if config.non_interactive {
+            return Err(format!(
+                "No password set, run interactively once to create {}",
+                password_path.display()
+            )
+            .into());
+        }
         let password = password_input!("Enter password: ");
-        let hash = sha256::digest(&password);
-        std::fs::write(password_path, hash)?;
-        password
-    };
+        app::set_password(&data_dir, &password).await?;
+    }
+
+    app::init_logging(config.log.level.as_deref(), config.log.format)?;
+    tracing::info!(data_dir = %data_dir.display(), storage = ?config.storage, "Starting");
+    config.data_dir = Some(data_dir);
+    app::run(config).await
+}
+
+async fn migrate(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cli = Cli::default();
+    let mut options = MigrateOptions::default();
+
+    let mut args_iter = args.iter().map(|s| s.as_str());
+    while let Some(arg) = args_iter.next() {
+        if subcommand_option(&mut cli, arg, &mut args_iter)? {
+            continue;
+        }
+        match arg {
+            "--overwrite" => options.overwrite = true,
+            "--dry-run" => options.dry_run = true,
+            _ => return Err(format!("Unknown migrate option {}", arg).into()),
+        }
+    }
+
+    let (_, data_dir) = subcommand_config(cli)?;
+    app::migrate(data_dir, options).await
+}
+
+async fn validate(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cli = Cli::default();
+    let mut fix = false;
+
+    let mut args_iter = args.iter().map(|s| s.as_str());
+    while let Some(arg) = args_iter.next() {
+        if subcommand_option(&mut cli, arg, &mut args_iter)? {
+            continue;
+        }
+        match arg {
+            "--storage" => {
+                let storage_str = args_iter.next().ok_or("Missing storage backend")?;
+                cli.storage = Some(storage_str.parse()?);
+            }
+            "--fix" => fix = true,
+            _ => return Err(format!("Unknown validate option {}", arg).into()),
+        }
+    }
+
+    let (config, data_dir) = subcommand_config(cli)?;
+    app::validate(data_dir, config.storage, fix).await
+}
+
+async fn rotate_key(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cli = Cli::default();
+    let mut grace_secs = None;
+
+    let mut args_iter = args.iter().map(|s| s.as_str());
+    while let Some(arg) = args_iter.next() {
+        if subcommand_option(&mut cli, arg, &mut args_iter)? {
+            continue;
+        }
+        match arg {
+            "--grace" => {
+                let grace_str = args_iter.next().ok_or("Missing grace period")?;
+                grace_secs = Some(grace_str.parse()?);
+            }
+            _ => return Err(format!("Unknown rotate-key option {}", arg).into()),
+        }
+    }
 
-    app::run(port, password, data_dir).await
+    let (config, data_dir) = subcommand_config(cli)?;
+    let grace_secs = grace_secs.unwrap_or(config.auth.key_grace_secs);
+    app::rotate_key(data_dir, grace_secs).await
 }
@@ -0,0 +1,288 @@
+use std::{
+    fmt::{self, Debug},
+    future::Future,
+    time::Instant,
+};
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, State},
+    http::{Request, Response},
+    middleware::Next,
+};
+use chrono::Local;
+use prometheus::{
+    exponential_buckets, histogram_opts, opts, Encoder, Gauge, HistogramVec, IntCounterVec,
+    IntGauge, Registry, TextEncoder,
+};
+
+use crate::{
+    currentblock::CurrentBlock,
+    err::{Error, ErrorType},
+    err_from_type, err_with_context,
+    storage::Storage,
+    timeblock::TimeBlock,
+};
+
+const NAMESPACE: &str = "timescheduler";
+
+/// Prometheus collectors for the server. Clones share the same collectors.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    auth_failures: IntCounterVec,
+    storage_duration: HistogramVec,
+    storage_errors: IntCounterVec,
+    current_block_type: IntGauge,
+    current_block_elapsed: Gauge,
+}
+
+impl Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, Error> {
+        Metrics::register().map_err(|e| err_with_context!(e, "Registering metrics"))
+    }
+
+    fn register() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            opts!("http_requests_total", "HTTP requests by route and status").namespace(NAMESPACE),
+            &["method", "route", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            histogram_opts!(
+                "http_request_duration_seconds",
+                "HTTP request latency by route"
+            )
+            .namespace(NAMESPACE),
+            &["method", "route"],
+        )?;
+        let auth_failures = IntCounterVec::new(
+            opts!(
+                "auth_failures_total",
+                "Rejected logins and tokens by reason"
+            )
+            .namespace(NAMESPACE),
+            &["reason"],
+        )?;
+        let storage_duration = HistogramVec::new(
+            histogram_opts!(
+                "storage_operation_duration_seconds",
+                "Storage read and write latency by operation",
+                exponential_buckets(0.0001, 4.0, 8)?
+            )
+            .namespace(NAMESPACE),
+            &["operation"],
+        )?;
+        let storage_errors = IntCounterVec::new(
+            opts!("storage_errors_total", "Failed storage operations").namespace(NAMESPACE),
+            &["operation"],
+        )?;
+
+        let current_block_type = IntGauge::with_opts(
+            opts!(
+                "current_block_type_id",
+                "Block type of the block running now"
+            )
+            .namespace(NAMESPACE),
+        )?;
+        let current_block_elapsed = Gauge::with_opts(
+            opts!(
+                "current_block_elapsed_seconds",
+                "Seconds since the running block started"
+            )
+            .namespace(NAMESPACE),
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(auth_failures.clone()))?;
+        registry.register(Box::new(storage_duration.clone()))?;
+        registry.register(Box::new(storage_errors.clone()))?;
+        registry.register(Box::new(current_block_type.clone()))?;
+        registry.register(Box::new(current_block_elapsed.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            requests,
+            request_duration,
+            auth_failures,
+            storage_duration,
+            storage_errors,
+            current_block_type,
+            current_block_elapsed,
+        })
+    }
+
+    pub fn auth_failure(&self, reason: &str) {
+        self.auth_failures.with_label_values(&[reason]).inc();
+    }
+
+    /// Run a storage operation, recording how long it took and whether it
+    /// failed.
+    pub async fn observe_storage<T, F>(&self, operation: &str, fut: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let start = Instant::now();
+        let res = fut.await;
+        self.storage_duration
+            .with_label_values(&[operation])
+            .observe(start.elapsed().as_secs_f64());
+        if res.is_err() {
+            self.storage_errors.with_label_values(&[operation]).inc();
+        }
+        res
+    }
+
+    /// Refresh the current block gauges and render every metric in the
+    /// Prometheus text format.
+    pub async fn render(&self, storage: &dyn Storage) -> Result<String, Error> {
+        let now = Local::now();
+        let today = now.date_naive();
+        let current_block = CurrentBlock::get(storage).await?;
+        let today_blocks = TimeBlock::get_day_timeblocks(storage, today).await?;
+        let running_since = TimeBlock::running_since(storage, today, &today_blocks).await?;
+        self.current_block_type
+            .set(i64::from(current_block.block_type_id));
+        self.current_block_elapsed
+            .set((now - running_since).num_milliseconds() as f64 / 1000.0);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| err_with_context!(e, "Encoding metrics"))?;
+        String::from_utf8(buffer).map_err(|e| {
+            err_from_type!(
+                ErrorType::InternalRustError,
+                "Metrics are not valid UTF-8: {}",
+                e
+            )
+        })
+    }
+}
+
+/// Count and time every request by its route template, so ids in paths do
+/// not blow up the label set.
+pub async fn track_requests(
+    State(metrics): State<Metrics>,
+    req: Request<Body>,
+    next: Next,
+) -> Response<Body> {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let start = Instant::now();
+    let response = next.run(req).await;
+    metrics
+        .request_duration
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    response
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use axum::{middleware::from_fn_with_state, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    async fn check(metrics: &Metrics, line: &str) -> bool {
+        let rendered = metrics
+            .render(&MemoryStorage::default())
+            .await
+            .ok()
+            .unwrap();
+        rendered.lines().any(|l| l == line)
+    }
+
+    #[tokio::test]
+    async fn collectors_move() {
+        let metrics = Metrics::new().ok().unwrap();
+        let routes = Router::new()
+            .route("/timeblock/{day}", get(|| async { "blocks" }))
+            .layer(from_fn_with_state(metrics.clone(), track_requests));
+        for day in ["2024-06-12", "2024-06-13"] {
+            let req = Request::get(format!("/timeblock/{}", day))
+                .body(Body::empty())
+                .unwrap();
+            routes.clone().oneshot(req).await.unwrap();
+        }
+        metrics.auth_failure("expired");
+        let ok = metrics.observe_storage("get_day", async { Ok(()) }).await;
+        assert!(ok.is_ok());
+        let failed = metrics
+            .observe_storage("save_day", async {
+                Err::<(), _>(err_from_type!(ErrorType::InternalRustError))
+            })
+            .await;
+        assert!(failed.is_err());
+
+        assert!(
+            check(
+                &metrics,
+                r#"timescheduler_http_requests_total{method="GET",route="/timeblock/{day}",status="200"} 2"#
+            )
+            .await
+        );
+        assert!(
+            check(
+                &metrics,
+                r#"timescheduler_http_request_duration_seconds_count{method="GET",route="/timeblock/{day}"} 2"#
+            )
+            .await
+        );
+        assert!(
+            check(
+                &metrics,
+                r#"timescheduler_auth_failures_total{reason="expired"} 1"#
+            )
+            .await
+        );
+        assert!(
+            check(
+                &metrics,
+                r#"timescheduler_storage_operation_duration_seconds_count{operation="get_day"} 1"#
+            )
+            .await
+        );
+        assert!(
+            check(
+                &metrics,
+                r#"timescheduler_storage_errors_total{operation="save_day"} 1"#
+            )
+            .await
+        );
+
+        let storage = MemoryStorage::default();
+        let current = CurrentBlock {
+            block_type_id: 2,
+            current_block_name: "reading".to_string(),
+        };
+        storage.save_current_block(&current).await.ok().unwrap();
+        let rendered = metrics.render(&storage).await.ok().unwrap();
+        assert!(rendered
+            .lines()
+            .any(|l| l == "timescheduler_current_block_type_id 2"));
+        assert!(rendered
+            .lines()
+            .any(|l| l.starts_with("timescheduler_current_block_elapsed_seconds ")));
+    }
+}
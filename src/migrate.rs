@@ -0,0 +1,591 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    atomicfile,
+    blocktype::BlockType,
+    currentblock::CurrentBlock,
+    datalock,
+    err::{Error, ErrorType},
+    err_from_type, err_with_context, health,
+    storage::sqlite,
+};
+
+/// Schema version written by this build of the server.
+pub const CURRENT_VERSION: u32 = 2;
+const VERSION_FILE: &str = "schema_version";
+const OUTPUT_DIR: &str = "migrations";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MigrateOptions {
+    /// Rewrite the data directory in place instead of into `migrations/`.
+    pub overwrite: bool,
+    /// Only report what would change.
+    pub dry_run: bool,
+}
+
+/// One step upgrading the data directory from `version() - 1` to `version()`.
+#[async_trait]
+trait Migration: Send + Sync {
+    fn version(&self) -> u32;
+    fn description(&self) -> &'static str;
+    async fn apply(&self, ctx: &mut MigrationContext) -> Result<(), Error>;
+}
+
+/// All migrations, in the order they have to be applied.
+fn migrations() -> Vec<Box<dyn Migration>> {
+    vec![Box::new(LegacyLayout), Box::new(TimeBlockIds)]
+}
+
+/// Time blocks as stored up to version 1.
+#[derive(Serialize, Deserialize)]
+struct TimeBlockV1 {
+    start_time: DateTime<FixedOffset>,
+    end_time: DateTime<FixedOffset>,
+    block_type_id: u8,
+    title: String,
+}
+
+/// Time blocks as stored from version 2.
+#[derive(Serialize, Deserialize)]
+struct TimeBlockV2 {
+    id: Uuid,
+    start_time: DateTime<FixedOffset>,
+    end_time: DateTime<FixedOffset>,
+    block_type_id: u8,
+    title: String,
+}
+
+/// Gives migration steps access to the directory being migrated and records
+/// every change so it can be reported, without writing anything on dry runs.
+struct MigrationContext {
+    dir: PathBuf,
+    dry_run: bool,
+    changes: Vec<String>,
+}
+
+impl MigrationContext {
+    fn path(&self, relative: &str) -> PathBuf {
+        self.dir.join(relative)
+    }
+
+    async fn read(&self, relative: &str) -> Result<Option<String>, Error> {
+        let path = self.path(relative);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(err_with_context!(e, "Reading {}", path.display())),
+        }
+    }
+
+    async fn write(&mut self, relative: &str, content: String) -> Result<(), Error> {
+        self.changes.push(format!("rewrite {}", relative));
+        if self.dry_run {
+            return Ok(());
+        }
+        atomicfile::write(&self.path(relative), content).await
+    }
+
+    async fn remove(&mut self, relative: &str) -> Result<(), Error> {
+        self.changes.push(format!("remove {}", relative));
+        if self.dry_run {
+            return Ok(());
+        }
+        let path = self.path(relative);
+        tokio::fs::remove_file(&path)
+            .await
+            .map_err(|e| err_with_context!(e, "Removing {}", path.display()))
+    }
+
+    /// Names of all day files under `timeblocks/`, sorted.
+    async fn day_files(&self) -> Result<Vec<String>, Error> {
+        let dir = self.path("timeblocks");
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", dir.display()))?;
+        let mut files = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", dir.display()))?
+        {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".json") {
+                files.push(format!("timeblocks/{}", name));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+/// 0.x data directories carry no version. Check every file parses in the
+/// current format, drop empty day files and sort the rest by start time.
+struct LegacyLayout;
+
+#[async_trait]
+impl Migration for LegacyLayout {
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn description(&self) -> &'static str {
+        "Validate legacy data files and sort day files by start time"
+    }
+
+    async fn apply(&self, ctx: &mut MigrationContext) -> Result<(), Error> {
+        if let Some(content) = ctx.read("blocktypes.json").await? {
+            serde_json::from_str::<Vec<BlockType>>(&content)
+                .map_err(|e| err_with_context!(e, "Deserializing blocktypes.json"))?;
+        }
+        if let Some(content) = ctx.read("currentblock.json").await? {
+            serde_json::from_str::<CurrentBlock>(&content)
+                .map_err(|e| err_with_context!(e, "Deserializing currentblock.json"))?;
+        }
+        for file in ctx.day_files().await? {
+            let Some(content) = ctx.read(&file).await? else {
+                continue;
+            };
+            if content.trim().is_empty() {
+                ctx.remove(&file).await?;
+                continue;
+            }
+            let mut timeblocks = serde_json::from_str::<Vec<TimeBlockV1>>(&content)
+                .map_err(|e| err_with_context!(e, "Deserializing {}", file))?;
+            timeblocks.sort_by_key(|b| b.start_time);
+            let sorted = serde_json::to_string_pretty(&timeblocks)
+                .map_err(|e| err_with_context!(e, "Serializing {}", file))?;
+            if sorted != content {
+                ctx.write(&file, sorted).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Give every time block a persistent id, in the day files and, if present,
+/// in the SQLite database. A database row gets the id of the day file block
+/// at the same day and position when both start at the same time, so the two
+/// backends agree on ids for the data they share.
+struct TimeBlockIds;
+
+#[async_trait]
+impl Migration for TimeBlockIds {
+    fn version(&self) -> u32 {
+        2
+    }
+
+    fn description(&self) -> &'static str {
+        "Assign unique ids to time blocks"
+    }
+
+    async fn apply(&self, ctx: &mut MigrationContext) -> Result<(), Error> {
+        let mut json_ids = HashMap::new();
+        for file in ctx.day_files().await? {
+            let Some(content) = ctx.read(&file).await? else {
+                continue;
+            };
+            let day = Path::new(&file)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok());
+            let timeblocks = serde_json::from_str::<Vec<TimeBlockV1>>(&content)
+                .map_err(|e| err_with_context!(e, "Deserializing {}", file))?
+                .into_iter()
+                .map(|b| TimeBlockV2 {
+                    id: Uuid::new_v4(),
+                    start_time: b.start_time,
+                    end_time: b.end_time,
+                    block_type_id: b.block_type_id,
+                    title: b.title,
+                })
+                .collect::<Vec<_>>();
+            if let Some(day) = day {
+                for (position, block) in timeblocks.iter().enumerate() {
+                    json_ids.insert((day, position), (block.start_time, block.id));
+                }
+            }
+            let content = serde_json::to_string_pretty(&timeblocks)
+                .map_err(|e| err_with_context!(e, "Serializing {}", file))?;
+            ctx.write(&file, content).await?;
+        }
+
+        let db_path = ctx.path("timescheduler.db");
+        if !db_path.exists() {
+            return Ok(());
+        }
+        ctx.changes
+            .push("add id column to timeblocks in timescheduler.db".to_string());
+        if ctx.dry_run {
+            return Ok(());
+        }
+        let mut conn = Connection::open(&db_path)
+            .map_err(|e| err_with_context!(e, "Opening {}", db_path.display()))?;
+        let tx = conn
+            .transaction()
+            .map_err(|e| err_with_context!(e, "Starting transaction"))?;
+        tx.execute("ALTER TABLE timeblocks ADD COLUMN id BLOB", [])
+            .map_err(|e| err_with_context!(e, "Adding id column"))?;
+        let rows = tx
+            .prepare("SELECT rowid, day, position, start_time FROM timeblocks")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, NaiveDate>(1)?,
+                        row.get::<_, usize>(2)?,
+                        row.get::<_, DateTime<FixedOffset>>(3)?,
+                    ))
+                })
+                .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            })
+            .map_err(|e| err_with_context!(e, "Reading timeblocks"))?;
+        for (rowid, day, position, start_time) in rows {
+            let id = match json_ids.get(&(day, position)) {
+                Some((json_start_time, id)) if *json_start_time == start_time => *id,
+                _ => Uuid::new_v4(),
+            };
+            tx.execute(
+                "UPDATE timeblocks SET id = ?1 WHERE rowid = ?2",
+                params![id, rowid],
+            )
+            .map_err(|e| err_with_context!(e, "Assigning id to timeblock {}", rowid))?;
+        }
+        tx.commit()
+            .map_err(|e| err_with_context!(e, "Committing timeblock ids"))
+    }
+}
+
+/// Version of the data in `data_dir`, 0 for directories written before
+/// versioning was introduced.
+pub async fn read_version(data_dir: &Path) -> Result<u32, Error> {
+    let path = data_dir.join(VERSION_FILE);
+    match tokio::fs::read_to_string(&path).await {
+        Ok(content) => content.trim().parse().map_err(|_| {
+            err_from_type!(
+                ErrorType::SchemaVersion,
+                "Invalid version in {}",
+                path.display()
+            )
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(err_with_context!(e, "Reading {}", path.display())),
+    }
+}
+
+async fn write_version(data_dir: &Path, version: u32) -> Result<(), Error> {
+    let path = data_dir.join(VERSION_FILE);
+    atomicfile::write(&path, format!("{}\n", version)).await
+}
+
+/// Make sure the server is started on data it understands. A data directory
+/// without any data is stamped with the current version.
+pub async fn check_version(data_dir: &Path) -> Result<(), Error> {
+    let version = read_version(data_dir).await?;
+    if version == CURRENT_VERSION {
+        return Ok(());
+    }
+    if version > CURRENT_VERSION {
+        return Err(err_from_type!(
+            ErrorType::SchemaVersion,
+            "Data directory is at version {} but this server only supports up to {}",
+            version,
+            CURRENT_VERSION
+        ));
+    }
+    let has_data = [
+        "timeblocks",
+        "blocktypes.json",
+        "currentblock.json",
+        "timescheduler.db",
+    ]
+    .iter()
+    .any(|name| data_dir.join(name).exists());
+    if version == 0 && !has_data {
+        return write_version(data_dir, CURRENT_VERSION).await;
+    }
+    Err(err_from_type!(
+        ErrorType::SchemaVersion,
+        "Data directory is at version {}, run `time-scheduler-server migrate` to upgrade to {}",
+        version,
+        CURRENT_VERSION
+    ))
+}
+
+/// Files a running server or an interrupted import leaves in the data
+/// directory, which are of no use in a copy.
+fn is_transient(name: &str) -> bool {
+    name == datalock::LOCK_FILE
+        || name == sqlite::IMPORTING_FILE
+        || name.starts_with(health::PROBE_PREFIX)
+}
+
+/// Copy the data directory into `target`, skipping the migrations folder itself
+/// and transient files.
+async fn copy_data_dir(source: &Path, target: &Path) -> Result<(), Error> {
+    tokio::fs::create_dir_all(target)
+        .await
+        .map_err(|e| err_with_context!(e, "Creating {}", target.display()))?;
+    let mut stack = vec![(source.to_path_buf(), target.to_path_buf())];
+    while let Some((from, to)) = stack.pop() {
+        let mut entries = tokio::fs::read_dir(&from)
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", from.display()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", from.display()))?
+        {
+            let path = entry.path();
+            if from == source
+                && (entry.file_name() == OUTPUT_DIR
+                    || is_transient(&entry.file_name().to_string_lossy()))
+            {
+                continue;
+            }
+            let dest = to.join(entry.file_name());
+            let file_type = entry
+                .file_type()
+                .await
+                .map_err(|e| err_with_context!(e, "Reading {}", path.display()))?;
+            if file_type.is_dir() {
+                tokio::fs::create_dir_all(&dest)
+                    .await
+                    .map_err(|e| err_with_context!(e, "Creating {}", dest.display()))?;
+                stack.push((path, dest));
+            } else {
+                tokio::fs::copy(&path, &dest)
+                    .await
+                    .map_err(|e| err_with_context!(e, "Copying {}", path.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Upgrade `data_dir` to `CURRENT_VERSION`, printing a report of every step.
+pub async fn migrate(data_dir: &Path, options: MigrateOptions) -> Result<(), Error> {
+    atomicfile::recover_data_dir(data_dir).await?;
+    let version = read_version(data_dir).await?;
+    let pending = migrations()
+        .into_iter()
+        .filter(|m| m.version() > version)
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        println!("Data directory is up to date at version {}", version);
+        return Ok(());
+    }
+
+    let target = if options.overwrite {
+        data_dir.to_path_buf()
+    } else {
+        let target = data_dir.join(OUTPUT_DIR);
+        // A dry run writes nothing, so an earlier output is no obstacle.
+        if target.exists() && !options.dry_run {
+            return Err(err_from_type!(
+                ErrorType::SchemaVersion,
+                "{} already exists, remove it first",
+                target.display()
+            ));
+        }
+        target
+    };
+    // Dry runs read the original data, nothing is copied.
+    let dir = if options.dry_run {
+        data_dir.to_path_buf()
+    } else {
+        if !options.overwrite {
+            copy_data_dir(data_dir, &target).await?;
+        }
+        target.clone()
+    };
+
+    let mut ctx = MigrationContext {
+        dir,
+        dry_run: options.dry_run,
+        changes: Vec::new(),
+    };
+    for migration in pending {
+        println!(
+            "Migrating to version {}: {}",
+            migration.version(),
+            migration.description()
+        );
+        migration.apply(&mut ctx).await?;
+        if ctx.changes.is_empty() {
+            println!("\tNo changes");
+        }
+        for change in ctx.changes.drain(..) {
+            println!("\t{}", change);
+        }
+        if !options.dry_run {
+            write_version(&target, migration.version()).await?;
+        }
+    }
+
+    if options.dry_run {
+        println!("Dry run, nothing was written");
+    } else {
+        println!(
+            "Migrated {} to version {}",
+            target.display(),
+            CURRENT_VERSION
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        storage::{json::JsonStorage, sqlite::SqliteStorage, Storage},
+        testing::{at, check},
+        timeblock::TimeBlock,
+    };
+
+    /// The `timeblocks` table before version 2 added the `id` column.
+    const V1_SCHEMA: &str = "
+    CREATE TABLE timeblocks (
+        day TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        start_time TEXT NOT NULL,
+        end_time TEXT NOT NULL,
+        block_type_id INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        PRIMARY KEY (day, position)
+    );
+    ";
+
+    fn ids(timeblocks: &[TimeBlock]) -> HashSet<Uuid> {
+        timeblocks.iter().map(|b| b.id).collect()
+    }
+
+    #[tokio::test]
+    async fn time_block_ids_are_added_to_json_and_sqlite() {
+        let dir = tempfile::tempdir().unwrap();
+        let day = at(0, 0).date_naive();
+        let v1 = (9..12)
+            .map(|h| TimeBlockV1 {
+                start_time: at(h, 0).fixed_offset(),
+                end_time: at(h + 1, 0).fixed_offset(),
+                block_type_id: 1,
+                title: format!("block {}", h),
+            })
+            .collect::<Vec<_>>();
+        std::fs::create_dir(dir.path().join("timeblocks")).unwrap();
+        std::fs::write(
+            dir.path().join("timeblocks").join("2024-06-12.json"),
+            serde_json::to_string(&v1).unwrap(),
+        )
+        .unwrap();
+        {
+            let conn = Connection::open(dir.path().join("timescheduler.db")).unwrap();
+            conn.execute_batch(V1_SCHEMA).unwrap();
+            for (position, block) in v1.iter().enumerate() {
+                conn.execute(
+                    "INSERT INTO timeblocks
+                     (day, position, start_time, end_time, block_type_id, title)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        day,
+                        position,
+                        block.start_time.with_timezone(&chrono::Local),
+                        block.end_time.with_timezone(&chrono::Local),
+                        block.block_type_id,
+                        block.title,
+                    ],
+                )
+                .unwrap();
+            }
+        }
+        check(write_version(dir.path(), 1).await);
+
+        let options = MigrateOptions {
+            overwrite: true,
+            dry_run: false,
+        };
+        check(migrate(dir.path(), options).await);
+        assert_eq!(check(read_version(dir.path()).await), 2);
+
+        let json = check(
+            JsonStorage::new(dir.path().to_path_buf())
+                .get_day_timeblocks(day)
+                .await,
+        );
+        let sqlite = check(
+            check(SqliteStorage::open(dir.path()).await)
+                .get_day_timeblocks(day)
+                .await,
+        );
+        for migrated in [&json, &sqlite] {
+            let spans = migrated
+                .iter()
+                .map(|b| {
+                    (
+                        b.start_time.fixed_offset(),
+                        b.end_time.fixed_offset(),
+                        b.title.as_str(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let expected = v1
+                .iter()
+                .map(|b| (b.start_time, b.end_time, b.title.as_str()))
+                .collect::<Vec<_>>();
+            assert_eq!(spans, expected);
+            assert_eq!(ids(migrated).len(), v1.len());
+        }
+        assert_eq!(ids(&json), ids(&sqlite));
+    }
+
+    #[tokio::test]
+    async fn copies_leave_out_transient_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("timeblocks")).unwrap();
+        std::fs::write(dir.path().join("timeblocks").join("2024-06-12.json"), "[]").unwrap();
+        for transient in [
+            datalock::LOCK_FILE,
+            sqlite::IMPORTING_FILE,
+            ".readyz.0123456789abcdef",
+        ] {
+            std::fs::write(dir.path().join(transient), "").unwrap();
+        }
+        check(write_version(dir.path(), 1).await);
+
+        // A dry run writes nothing, so an earlier output is no obstacle.
+        let output = dir.path().join(OUTPUT_DIR);
+        std::fs::create_dir(&output).unwrap();
+        let dry_run = MigrateOptions {
+            overwrite: false,
+            dry_run: true,
+        };
+        check(migrate(dir.path(), dry_run).await);
+        assert!(migrate(dir.path(), MigrateOptions::default())
+            .await
+            .is_err());
+
+        std::fs::remove_dir(&output).unwrap();
+        check(migrate(dir.path(), MigrateOptions::default()).await);
+        let mut copied = std::fs::read_dir(&output)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        copied.sort();
+        assert_eq!(copied, [VERSION_FILE, "timeblocks"]);
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    err::{Error, ErrorType},
+    err_from_type, err_with_context,
+    timeblock::TimeBlock,
+};
+
+/// Strong entity tag for a day's time blocks, derived from their contents so
+/// every backend yields the same revision for the same data.
+pub fn of(timeblocks: &[TimeBlock]) -> Result<String, Error> {
+    let content = serde_json::to_string(timeblocks)
+        .map_err(|e| err_with_context!(e, "Serializing timeblocks for revision"))?;
+    Ok(format!("\"{}\"", &sha256::digest(content)[..16]))
+}
+
+/// Whether an `If-Match` header value admits `etag`.
+fn matches(if_match: &str, etag: &str) -> bool {
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// Fail with `PreconditionFailed` if the client sent `If-Match` and it does not
+/// name the current revision of `timeblocks`.
+pub fn check(if_match: Option<&str>, timeblocks: &[TimeBlock]) -> Result<(), Error> {
+    let Some(if_match) = if_match else {
+        return Ok(());
+    };
+    let etag = of(timeblocks)?;
+    if matches(if_match, &etag) {
+        return Ok(());
+    }
+    Err(err_from_type!(
+        ErrorType::PreconditionFailed,
+        "Expected {} but day is at {}",
+        if_match,
+        etag
+    ))
+}
@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Method, Request, Response, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use tokio::sync::{OwnedRwLockWriteGuard, RwLock};
+
+use crate::{
+    err::{Error, ErrorType},
+    err_from_type,
+};
+
+/// Same as axum's default body limit, which the extractors apply again.
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Resolve on the first SIGINT or, on unix, SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "Listening for SIGINT failed");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Listening for SIGTERM failed");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => tracing::info!("Received SIGINT"),
+        () = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+/// Requests that may write to storage. Each runs on its own task holding a
+/// read guard, so it finishes even if the client disconnects or the drain
+/// timeout passes, and shutdown can wait for all of them by taking the
+/// write guard.
+#[derive(Debug, Clone, Default)]
+pub struct Writes(Arc<RwLock<()>>);
+
+impl Writes {
+    /// Wait for every running write and keep new ones from starting for as
+    /// long as the guard is held.
+    pub async fn finish(&self) -> OwnedRwLockWriteGuard<()> {
+        self.0.clone().write_owned().await
+    }
+}
+
+/// Run every request that is not a read to completion on its own task, so
+/// a write spanning several day files is never cut off halfway.
+///
+/// The body is read first so a slow client can only hold up its own
+/// connection, never the wait for writes on shutdown.
+pub async fn complete_writes(
+    State(writes): State<Writes>,
+    req: Request<Body>,
+    next: Next,
+) -> Response<Body> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+    let (parts, body) = req.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(e) => {
+            let err: Error = err_from_type!(
+                ErrorType::Rejection(StatusCode::BAD_REQUEST),
+                "Reading request body: {}",
+                e
+            );
+            return err.into_response();
+        }
+    };
+    let req = Request::from_parts(parts, Body::from(body));
+    let guard = writes.0.clone().read_owned().await;
+    let task = tokio::spawn(async move {
+        let response = next.run(req).await;
+        drop(guard);
+        response
+    });
+    match task.await {
+        Ok(response) => response,
+        Err(e) => {
+            let err: Error =
+                err_from_type!(ErrorType::InternalRustError, "Write task failed: {}", e);
+            err.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use axum::{middleware::from_fn_with_state, routing::post, Router};
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn dropped_writes_finish_before_shutdown_continues() {
+        let writes = Writes::default();
+        let started = Arc::new(Notify::new());
+        let saved = Arc::new(AtomicBool::new(false));
+        let routes = {
+            let started = started.clone();
+            let saved = saved.clone();
+            Router::new()
+                .route(
+                    "/",
+                    post(move || async move {
+                        started.notify_one();
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        saved.store(true, Ordering::SeqCst);
+                    })
+                    .get(|| async { "read" }),
+                )
+                .layer(from_fn_with_state(writes.clone(), complete_writes))
+        };
+
+        let request = Request::post("/").body(Body::from("{}")).unwrap();
+        let client = tokio::spawn(routes.clone().oneshot(request));
+        started.notified().await;
+        // The connection is dropped, as when the drain timeout passes.
+        client.abort();
+
+        let _no_more_writes = writes.finish().await;
+        assert!(saved.load(Ordering::SeqCst));
+
+        // Reads are still answered while writes are held off.
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = routes.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, fmt::Debug, str::FromStr};
+
+use async_trait::async_trait;
+use chrono::{Days, NaiveDate};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    blocktype::BlockType,
+    currentblock::CurrentBlock,
+    err::{Error, ErrorType},
+    err_from_type,
+    timeblock::TimeBlock,
+};
+
+pub mod json;
+#[cfg(test)]
+pub mod memory;
+pub mod metered;
+pub mod sqlite;
+
+/// Which `Storage` implementation the server persists its data with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    Json,
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(StorageKind::Json),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err(format!("Unknown storage backend {}", s)),
+        }
+    }
+}
+
+/// Persistence backend for everything the server stores.
+///
+/// Implementations only move data in and out; defaults and domain rules live
+/// in `TimeBlock`, `BlockType` and `CurrentBlock`.
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Time blocks recorded for `day`, empty if nothing was recorded.
+    async fn get_day_timeblocks(&self, day: NaiveDate) -> Result<Vec<TimeBlock>, Error>;
+    /// Replace all time blocks recorded for `day`.
+    async fn save_day_timeblocks(
+        &self,
+        day: NaiveDate,
+        timeblocks: &[TimeBlock],
+    ) -> Result<(), Error>;
+    /// Every day that has time blocks recorded, in ascending order.
+    async fn list_days(&self) -> Result<Vec<NaiveDate>, Error>;
+
+    /// Time blocks for every day from `start` to `end` inclusive, keyed by day.
+    /// Days without records map to an empty list.
+    async fn get_range_timeblocks(
+        &self,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<BTreeMap<NaiveDate, Vec<TimeBlock>>, Error> {
+        let mut days = BTreeMap::new();
+        let mut day = start;
+        while day <= end {
+            days.insert(day, self.get_day_timeblocks(day).await?);
+            day = day.checked_add_days(Days::new(1)).ok_or(err_from_type!(
+                ErrorType::Chrono,
+                "Creating day after {}",
+                day.format("%Y-%m-%d")
+            ))?;
+        }
+        Ok(days)
+    }
+
+    /// The day the time block `id` is recorded on, `None` if there is no such
+    /// block.
+    async fn find_timeblock_day(&self, id: Uuid) -> Result<Option<NaiveDate>, Error> {
+        // Latest days first, where blocks are usually edited.
+        for day in self.list_days().await?.into_iter().rev() {
+            if self
+                .get_day_timeblocks(day)
+                .await?
+                .iter()
+                .any(|b| b.id == id)
+            {
+                return Ok(Some(day));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stored block types, `None` if they were never saved.
+    async fn load_blocktypes(&self) -> Result<Option<Vec<BlockType>>, Error>;
+    async fn save_blocktypes(&self, blocktypes: &[BlockType]) -> Result<(), Error>;
+
+    /// Stored current block, `None` if it was never saved.
+    async fn get_current_block(&self) -> Result<Option<CurrentBlock>, Error>;
+    async fn save_current_block(&self, current_block: &CurrentBlock) -> Result<(), Error>;
+
+    /// Make everything saved so far durable. Called once on shutdown after
+    /// the last write; backends that sync on every save need nothing here.
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+
+use crate::{
+    atomicfile, blocktype::BlockType, currentblock::CurrentBlock, err::Error, err_with_context,
+    timeblock::TimeBlock,
+};
+
+use super::Storage;
+
+/// The original on-disk layout: one `timeblocks/YYYY-MM-DD.json` file per day
+/// plus `blocktypes.json` and `currentblock.json` in the data directory.
+#[derive(Debug, Clone)]
+pub struct JsonStorage {
+    data_dir: PathBuf,
+}
+
+impl JsonStorage {
+    pub fn new(data_dir: PathBuf) -> Self {
+        JsonStorage { data_dir }
+    }
+
+    fn day_file(&self, day: NaiveDate) -> PathBuf {
+        self.data_dir
+            .join("timeblocks")
+            .join(format!("{}.json", day.format("%Y-%m-%d")))
+    }
+}
+
+#[async_trait]
+impl Storage for JsonStorage {
+    async fn get_day_timeblocks(&self, day: NaiveDate) -> Result<Vec<TimeBlock>, Error> {
+        let time_blocks_dir = self.data_dir.join("timeblocks");
+        if !time_blocks_dir.exists() {
+            // Concurrent requests may race to create it.
+            tokio::fs::create_dir_all(&time_blocks_dir)
+                .await
+                .map_err(|e| err_with_context!(e, "Creating timeblocks directory"))?;
+        }
+
+        let file_name = self.day_file(day);
+        let file = tokio::fs::File::open(&file_name).await;
+        if let Err(e) = &file {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                return Ok(vec![]);
+            }
+        }
+        let mut file = file.map_err(|e| err_with_context!(e, "Opening {}", file_name.display()))?;
+        let mut content = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut file, &mut content)
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", file_name.display()))?;
+        if content.is_empty() {
+            return Ok(vec![]);
+        }
+        let timeblocks: Vec<TimeBlock> = serde_json::from_str(&content)
+            .map_err(|e| err_with_context!(e, "Deserializing {}", file_name.display()))?;
+        Ok(timeblocks)
+    }
+
+    async fn save_day_timeblocks(
+        &self,
+        day: NaiveDate,
+        timeblocks: &[TimeBlock],
+    ) -> Result<(), Error> {
+        let time_blocks_dir = self.data_dir.join("timeblocks");
+        if !time_blocks_dir.exists() {
+            // Concurrent requests may race to create it.
+            tokio::fs::create_dir_all(&time_blocks_dir)
+                .await
+                .map_err(|e| err_with_context!(e, "Creating timeblocks directory"))?;
+        }
+
+        let file_name = self.day_file(day);
+        let content = serde_json::to_string_pretty(timeblocks)
+            .map_err(|e| err_with_context!(e, "Serializing {}", file_name.display()))?;
+        atomicfile::write(&file_name, content).await
+    }
+
+    async fn list_days(&self) -> Result<Vec<NaiveDate>, Error> {
+        let time_blocks_dir = self.data_dir.join("timeblocks");
+        if !time_blocks_dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut entries = tokio::fs::read_dir(&time_blocks_dir)
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", time_blocks_dir.display()))?;
+        let mut days = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", time_blocks_dir.display()))?
+        {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if let Ok(day) = NaiveDate::parse_from_str(stem, "%Y-%m-%d") {
+                days.push(day);
+            }
+        }
+        days.sort();
+        Ok(days)
+    }
+
+    async fn load_blocktypes(&self) -> Result<Option<Vec<BlockType>>, Error> {
+        let blocktypes_path = self.data_dir.join("blocktypes.json");
+        if !blocktypes_path.exists() {
+            return Ok(None);
+        }
+        let content = tokio::fs::read_to_string(&blocktypes_path)
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", blocktypes_path.display()))?;
+        let blocktypes = serde_json::from_str::<Vec<BlockType>>(&content)
+            .map_err(|e| err_with_context!(e, "Deserializing {}", blocktypes_path.display()))?;
+        Ok(Some(blocktypes))
+    }
+
+    async fn save_blocktypes(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        let blocktypes_path = self.data_dir.join("blocktypes.json");
+        let contents = serde_json::to_string_pretty(blocktypes)
+            .map_err(|e| err_with_context!(e, "Serializing to {}", blocktypes_path.display()))?;
+        atomicfile::write(&blocktypes_path, contents).await
+    }
+
+    async fn get_current_block(&self) -> Result<Option<CurrentBlock>, Error> {
+        let current_block_file = self.data_dir.join("currentblock.json");
+        if !current_block_file.exists() {
+            return Ok(None);
+        }
+        let currrent_data_file = tokio::fs::read_to_string(&current_block_file)
+            .await
+            .map_err(|e| err_with_context!(e, "Reading {}", current_block_file.display()))?;
+        let res = serde_json::from_str(&currrent_data_file)
+            .map_err(|e| err_with_context!(e, "Deserializing {}", current_block_file.display()))?;
+        Ok(Some(res))
+    }
+
+    async fn save_current_block(&self, current_block: &CurrentBlock) -> Result<(), Error> {
+        let current_block_file = self.data_dir.join("currentblock.json");
+        let data = serde_json::to_string(current_block)
+            .map_err(|e| err_with_context!(e, "Serializing {}", current_block_file.display()))?;
+        atomicfile::write(&current_block_file, data).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::testing::{at, check, save_blocktypes};
+
+    #[tokio::test]
+    async fn days_are_listed_and_read_in_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = JsonStorage::new(dir.path().to_path_buf());
+        assert!(check(storage.list_days().await).is_empty());
+        assert!(check(storage.load_blocktypes().await).is_none());
+        assert!(check(storage.get_current_block().await).is_none());
+
+        let day = at(0, 0).date_naive();
+        let blocks_of = |offset: u64| {
+            let start = at(9, 0) + chrono::Days::new(offset);
+            vec![TimeBlock::new(
+                start,
+                start + chrono::Duration::hours(1),
+                0,
+                format!("day {}", offset),
+            )]
+        };
+        let mut saved = BTreeMap::new();
+        for offset in [2, 0, 4] {
+            let blocks = blocks_of(offset);
+            let day = day + chrono::Days::new(offset);
+            check(storage.save_day_timeblocks(day, &blocks).await);
+            saved.insert(offset, blocks);
+        }
+        // Files that are not day files are ignored.
+        let timeblocks_dir = dir.path().join("timeblocks");
+        std::fs::write(timeblocks_dir.join("notes.json"), "[]").unwrap();
+        std::fs::write(timeblocks_dir.join("2024-06-13.json.1.ab.tmp"), "[]").unwrap();
+
+        let days = check(storage.list_days().await);
+        assert_eq!(
+            days,
+            [0, 2, 4].map(|offset| day + chrono::Days::new(offset))
+        );
+
+        let range = check(
+            storage
+                .get_range_timeblocks(day + chrono::Days::new(1), day + chrono::Days::new(4))
+                .await,
+        );
+        assert_eq!(range.len(), 4);
+        for (offset, (range_day, timeblocks)) in (1..=4).zip(&range) {
+            assert_eq!(*range_day, day + chrono::Days::new(offset));
+            assert_eq!(*timeblocks, saved.remove(&offset).unwrap_or_default());
+        }
+
+        save_blocktypes(&storage).await;
+        assert_eq!(check(storage.load_blocktypes().await).unwrap().len(), 3);
+        let current = CurrentBlock {
+            block_type_id: 2,
+            current_block_name: "reading".to_string(),
+        };
+        check(storage.save_current_block(&current).await);
+        let stored = check(storage.get_current_block().await).unwrap();
+        assert_eq!(stored.block_type_id, 2);
+        assert_eq!(stored.current_block_name, "reading");
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use tokio::sync::Mutex;
+
+use crate::{blocktype::BlockType, currentblock::CurrentBlock, err::Error, timeblock::TimeBlock};
+
+use super::Storage;
+
+/// Keeps everything in memory, for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    days: Mutex<HashMap<NaiveDate, Vec<TimeBlock>>>,
+    blocktypes: Mutex<Option<Vec<BlockType>>>,
+    current_block: Mutex<Option<CurrentBlock>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get_day_timeblocks(&self, day: NaiveDate) -> Result<Vec<TimeBlock>, Error> {
+        let timeblocks = self.days.lock().await.get(&day).cloned();
+        // Yield so concurrent callers interleave the way they would on real I/O.
+        tokio::task::yield_now().await;
+        Ok(timeblocks.unwrap_or_default())
+    }
+
+    async fn save_day_timeblocks(
+        &self,
+        day: NaiveDate,
+        timeblocks: &[TimeBlock],
+    ) -> Result<(), Error> {
+        tokio::task::yield_now().await;
+        self.days.lock().await.insert(day, timeblocks.to_vec());
+        Ok(())
+    }
+
+    async fn list_days(&self) -> Result<Vec<NaiveDate>, Error> {
+        let mut days = self.days.lock().await.keys().copied().collect::<Vec<_>>();
+        days.sort();
+        Ok(days)
+    }
+
+    async fn load_blocktypes(&self) -> Result<Option<Vec<BlockType>>, Error> {
+        Ok(self.blocktypes.lock().await.clone())
+    }
+
+    async fn save_blocktypes(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        *self.blocktypes.lock().await = Some(blocktypes.to_vec());
+        Ok(())
+    }
+
+    async fn get_current_block(&self) -> Result<Option<CurrentBlock>, Error> {
+        Ok(self.current_block.lock().await.clone())
+    }
+
+    async fn save_current_block(&self, current_block: &CurrentBlock) -> Result<(), Error> {
+        *self.current_block.lock().await = Some(current_block.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use uuid::Uuid;
+
+use super::Storage;
+use crate::{
+    blocktype::BlockType, currentblock::CurrentBlock, err::Error, metrics::Metrics,
+    timeblock::TimeBlock,
+};
+
+/// Wraps another backend, recording the duration and failures of every
+/// operation in `Metrics`.
+#[derive(Debug)]
+pub struct MeteredStorage {
+    inner: Arc<dyn Storage>,
+    metrics: Metrics,
+}
+
+impl MeteredStorage {
+    pub fn new(inner: Arc<dyn Storage>, metrics: Metrics) -> Self {
+        MeteredStorage { inner, metrics }
+    }
+}
+
+#[async_trait]
+impl Storage for MeteredStorage {
+    async fn get_day_timeblocks(&self, day: NaiveDate) -> Result<Vec<TimeBlock>, Error> {
+        self.metrics
+            .observe_storage("get_day_timeblocks", self.inner.get_day_timeblocks(day))
+            .await
+    }
+
+    async fn save_day_timeblocks(
+        &self,
+        day: NaiveDate,
+        timeblocks: &[TimeBlock],
+    ) -> Result<(), Error> {
+        self.metrics
+            .observe_storage(
+                "save_day_timeblocks",
+                self.inner.save_day_timeblocks(day, timeblocks),
+            )
+            .await
+    }
+
+    async fn list_days(&self) -> Result<Vec<NaiveDate>, Error> {
+        self.metrics
+            .observe_storage("list_days", self.inner.list_days())
+            .await
+    }
+
+    async fn get_range_timeblocks(
+        &self,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<BTreeMap<NaiveDate, Vec<TimeBlock>>, Error> {
+        self.metrics
+            .observe_storage(
+                "get_range_timeblocks",
+                self.inner.get_range_timeblocks(start, end),
+            )
+            .await
+    }
+
+    async fn find_timeblock_day(&self, id: Uuid) -> Result<Option<NaiveDate>, Error> {
+        self.metrics
+            .observe_storage("find_timeblock_day", self.inner.find_timeblock_day(id))
+            .await
+    }
+
+    async fn load_blocktypes(&self) -> Result<Option<Vec<BlockType>>, Error> {
+        self.metrics
+            .observe_storage("load_blocktypes", self.inner.load_blocktypes())
+            .await
+    }
+
+    async fn save_blocktypes(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        self.metrics
+            .observe_storage("save_blocktypes", self.inner.save_blocktypes(blocktypes))
+            .await
+    }
+
+    async fn get_current_block(&self) -> Result<Option<CurrentBlock>, Error> {
+        self.metrics
+            .observe_storage("get_current_block", self.inner.get_current_block())
+            .await
+    }
+
+    async fn save_current_block(&self, current_block: &CurrentBlock) -> Result<(), Error> {
+        self.metrics
+            .observe_storage(
+                "save_current_block",
+                self.inner.save_current_block(current_block),
+            )
+            .await
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        self.metrics
+            .observe_storage("flush", self.inner.flush())
+            .await
+    }
+}
@@ -0,0 +1,454 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use uuid::Uuid;
+
+use crate::{
+    atomicfile,
+    blocktype::{BlockType, Color},
+    currentblock::CurrentBlock,
+    err::{Error, ErrorType},
+    err_from_type, err_with_context,
+    timeblock::TimeBlock,
+};
+
+use super::{json::JsonStorage, Storage};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS timeblocks (
+    day TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    id BLOB NOT NULL,
+    start_time TEXT NOT NULL,
+    end_time TEXT NOT NULL,
+    block_type_id INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    PRIMARY KEY (day, position)
+);
+CREATE INDEX IF NOT EXISTS timeblocks_id ON timeblocks (id);
+CREATE TABLE IF NOT EXISTS blocktypes (
+    position INTEGER PRIMARY KEY,
+    id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    r INTEGER NOT NULL,
+    g INTEGER NOT NULL,
+    b INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS current_block (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    block_type_id INTEGER NOT NULL,
+    current_block_name TEXT NOT NULL
+);
+";
+
+const DB_FILE: &str = "timescheduler.db";
+/// The database while it is seeded from JSON data, renamed to `DB_FILE` once
+/// the import is complete.
+pub const IMPORTING_FILE: &str = "timescheduler.db.importing";
+
+/// Everything in a single bundled SQLite database, `timescheduler.db` in the
+/// data directory. Time blocks are keyed by `(day, position)` so range queries
+/// are served from the primary key index, and indexed by id.
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    db_path: PathBuf,
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Open (or create) the database in `data_dir`. A freshly created database
+    /// is seeded from any JSON data already present in `data_dir`.
+    pub async fn open(data_dir: &Path) -> Result<Self, Error> {
+        let db_path = data_dir.join(DB_FILE);
+        if !db_path.exists() {
+            Self::create(data_dir, &db_path).await?;
+        }
+        Self::connect(db_path)
+    }
+
+    fn connect(db_path: PathBuf) -> Result<Self, Error> {
+        let conn = Connection::open(&db_path)
+            .map_err(|e| err_with_context!(e, "Opening {}", db_path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .map_err(|e| err_with_context!(e, "Creating schema in {}", db_path.display()))?;
+        Ok(SqliteStorage {
+            db_path,
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Import the JSON data in `data_dir` into a new database at `db_path`.
+    /// The database only appears there once everything is imported, so an
+    /// import that fails is simply run again on the next start.
+    async fn create(data_dir: &Path, db_path: &Path) -> Result<(), Error> {
+        let importing = data_dir.join(IMPORTING_FILE);
+        if importing.exists() {
+            tracing::warn!(path = %importing.display(), "Discarding interrupted import");
+            tokio::fs::remove_file(&importing)
+                .await
+                .map_err(|e| err_with_context!(e, "Removing {}", importing.display()))?;
+        }
+        let storage = Self::connect(importing.clone())?;
+        storage
+            .import(&JsonStorage::new(data_dir.to_path_buf()))
+            .await?;
+        drop(storage);
+        tokio::fs::rename(&importing, db_path).await.map_err(|e| {
+            err_with_context!(
+                e,
+                "Renaming {} to {}",
+                importing.display(),
+                db_path.display()
+            )
+        })?;
+        atomicfile::sync_dir(data_dir).await
+    }
+
+    /// Copy everything held by `source` into this database.
+    pub async fn import(&self, source: &dyn Storage) -> Result<(), Error> {
+        for day in source.list_days().await? {
+            let timeblocks = source.get_day_timeblocks(day).await?;
+            self.save_day_timeblocks(day, &timeblocks).await?;
+        }
+        if let Some(blocktypes) = source.load_blocktypes().await? {
+            self.save_blocktypes(&blocktypes).await?;
+        }
+        if let Some(current_block) = source.get_current_block().await? {
+            self.save_current_block(&current_block).await?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` against the connection on the blocking thread pool.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let db_path = self.db_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().map_err(|_| {
+                err_from_type!(
+                    ErrorType::InternalRustError,
+                    "Connection to {} poisoned",
+                    db_path.display()
+                )
+            })?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| err_from_type!(ErrorType::InternalRustError, "Joining database task: {}", e))?
+    }
+}
+
+fn timeblock_from_row(row: &Row) -> rusqlite::Result<TimeBlock> {
+    Ok(TimeBlock {
+        id: row.get("id")?,
+        start_time: row.get("start_time")?,
+        end_time: row.get("end_time")?,
+        block_type_id: row.get("block_type_id")?,
+        title: row.get("title")?,
+    })
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_day_timeblocks(&self, day: NaiveDate) -> Result<Vec<TimeBlock>, Error> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn
+                .prepare_cached(
+                    "SELECT id, start_time, end_time, block_type_id, title FROM timeblocks
+                     WHERE day = ?1 ORDER BY position",
+                )
+                .map_err(|e| err_with_context!(e, "Preparing timeblocks query"))?;
+            let timeblocks = stmt
+                .query_map(params![day], timeblock_from_row)
+                .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+                .map_err(|e| {
+                    err_with_context!(e, "Reading timeblocks for {}", day.format("%Y-%m-%d"))
+                })?;
+            Ok(timeblocks)
+        })
+        .await
+    }
+
+    async fn save_day_timeblocks(
+        &self,
+        day: NaiveDate,
+        timeblocks: &[TimeBlock],
+    ) -> Result<(), Error> {
+        let timeblocks = timeblocks.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn
+                .transaction()
+                .map_err(|e| err_with_context!(e, "Starting transaction"))?;
+            tx.execute("DELETE FROM timeblocks WHERE day = ?1", params![day])
+                .map_err(|e| {
+                    err_with_context!(e, "Clearing timeblocks for {}", day.format("%Y-%m-%d"))
+                })?;
+            {
+                let mut stmt = tx
+                    .prepare_cached(
+                        "INSERT INTO timeblocks
+                         (day, position, id, start_time, end_time, block_type_id, title)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    )
+                    .map_err(|e| err_with_context!(e, "Preparing timeblocks insert"))?;
+                for (position, block) in timeblocks.iter().enumerate() {
+                    stmt.execute(params![
+                        day,
+                        position,
+                        block.id,
+                        block.start_time,
+                        block.end_time,
+                        block.block_type_id,
+                        block.title,
+                    ])
+                    .map_err(|e| {
+                        err_with_context!(e, "Writing timeblocks for {}", day.format("%Y-%m-%d"))
+                    })?;
+                }
+            }
+            tx.commit()
+                .map_err(|e| err_with_context!(e, "Committing timeblocks"))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_days(&self) -> Result<Vec<NaiveDate>, Error> {
+        self.with_conn(|conn| {
+            let mut stmt = conn
+                .prepare_cached("SELECT DISTINCT day FROM timeblocks ORDER BY day")
+                .map_err(|e| err_with_context!(e, "Preparing days query"))?;
+            let days = stmt
+                .query_map([], |row| row.get(0))
+                .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+                .map_err(|e| err_with_context!(e, "Listing days"))?;
+            Ok(days)
+        })
+        .await
+    }
+
+    async fn get_range_timeblocks(
+        &self,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<BTreeMap<NaiveDate, Vec<TimeBlock>>, Error> {
+        let mut days: BTreeMap<NaiveDate, Vec<TimeBlock>> = start
+            .iter_days()
+            .take_while(|day| *day <= end)
+            .map(|day| (day, vec![]))
+            .collect();
+        let rows = self
+            .with_conn(move |conn| {
+                let mut stmt = conn
+                    .prepare_cached(
+                        "SELECT day, id, start_time, end_time, block_type_id, title FROM timeblocks
+                         WHERE day BETWEEN ?1 AND ?2 ORDER BY day, position",
+                    )
+                    .map_err(|e| err_with_context!(e, "Preparing timeblocks range query"))?;
+                let rows = stmt
+                    .query_map(params![start, end], |row| {
+                        Ok((row.get::<_, NaiveDate>("day")?, timeblock_from_row(row)?))
+                    })
+                    .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+                    .map_err(|e| {
+                        err_with_context!(
+                            e,
+                            "Reading timeblocks from {} to {}",
+                            start.format("%Y-%m-%d"),
+                            end.format("%Y-%m-%d")
+                        )
+                    })?;
+                Ok(rows)
+            })
+            .await?;
+        for (day, block) in rows {
+            days.entry(day).or_default().push(block);
+        }
+        Ok(days)
+    }
+
+    async fn find_timeblock_day(&self, id: Uuid) -> Result<Option<NaiveDate>, Error> {
+        self.with_conn(move |conn| {
+            conn.prepare_cached("SELECT day FROM timeblocks WHERE id = ?1")
+                .and_then(|mut stmt| stmt.query_row(params![id], |row| row.get(0)).optional())
+                .map_err(|e| err_with_context!(e, "Finding time block {}", id))
+        })
+        .await
+    }
+
+    async fn load_blocktypes(&self) -> Result<Option<Vec<BlockType>>, Error> {
+        self.with_conn(|conn| {
+            let mut stmt = conn
+                .prepare_cached("SELECT id, name, r, g, b FROM blocktypes ORDER BY position")
+                .map_err(|e| err_with_context!(e, "Preparing blocktypes query"))?;
+            let blocktypes = stmt
+                .query_map([], |row| {
+                    Ok(BlockType {
+                        id: row.get("id")?,
+                        name: row.get("name")?,
+                        color: Color {
+                            r: row.get("r")?,
+                            g: row.get("g")?,
+                            b: row.get("b")?,
+                        },
+                    })
+                })
+                .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+                .map_err(|e| err_with_context!(e, "Reading blocktypes"))?;
+            if blocktypes.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(blocktypes))
+        })
+        .await
+    }
+
+    async fn save_blocktypes(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        let blocktypes = blocktypes.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn
+                .transaction()
+                .map_err(|e| err_with_context!(e, "Starting transaction"))?;
+            tx.execute("DELETE FROM blocktypes", [])
+                .map_err(|e| err_with_context!(e, "Clearing blocktypes"))?;
+            {
+                let mut stmt = tx
+                    .prepare_cached(
+                        "INSERT INTO blocktypes (position, id, name, r, g, b)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    )
+                    .map_err(|e| err_with_context!(e, "Preparing blocktypes insert"))?;
+                for (position, blocktype) in blocktypes.iter().enumerate() {
+                    stmt.execute(params![
+                        position,
+                        blocktype.id,
+                        blocktype.name,
+                        blocktype.color.r,
+                        blocktype.color.g,
+                        blocktype.color.b,
+                    ])
+                    .map_err(|e| err_with_context!(e, "Writing blocktype {}", blocktype.id))?;
+                }
+            }
+            tx.commit()
+                .map_err(|e| err_with_context!(e, "Committing blocktypes"))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_current_block(&self) -> Result<Option<CurrentBlock>, Error> {
+        self.with_conn(|conn| {
+            conn.query_row(
+                "SELECT block_type_id, current_block_name FROM current_block WHERE id = 0",
+                [],
+                |row| {
+                    Ok(CurrentBlock {
+                        block_type_id: row.get("block_type_id")?,
+                        current_block_name: row.get("current_block_name")?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(|e| err_with_context!(e, "Reading current block"))
+        })
+        .await
+    }
+
+    async fn save_current_block(&self, current_block: &CurrentBlock) -> Result<(), Error> {
+        let block_type_id = current_block.block_type_id;
+        let current_block_name = current_block.current_block_name.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO current_block (id, block_type_id, current_block_name)
+                 VALUES (0, ?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET
+                    block_type_id = excluded.block_type_id,
+                    current_block_name = excluded.current_block_name",
+                params![block_type_id, current_block_name],
+            )
+            .map_err(|e| err_with_context!(e, "Writing current block"))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        self.with_conn(|conn| {
+            conn.cache_flush()
+                .map_err(|e| err_with_context!(e, "Flushing database cache"))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::testing::{at, check, save_blocktypes};
+
+    async fn dump(storage: &dyn Storage) -> String {
+        let days = check(storage.list_days().await);
+        let mut timeblocks = Vec::new();
+        for day in &days {
+            timeblocks.push(check(storage.get_day_timeblocks(*day).await));
+        }
+        let blocktypes = check(storage.load_blocktypes().await);
+        let current_block = check(storage.get_current_block().await);
+        serde_json::json!([days, timeblocks, blocktypes, current_block]).to_string()
+    }
+
+    #[tokio::test]
+    async fn json_data_is_imported_completely_or_not_at_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = JsonStorage::new(dir.path().to_path_buf());
+        let day = at(0, 0).date_naive();
+        let next_day = day.succ_opt().unwrap();
+        save_blocktypes(&json).await;
+        check(
+            json.save_current_block(&CurrentBlock {
+                block_type_id: 1,
+                current_block_name: "writing".to_string(),
+            })
+            .await,
+        );
+        let blocks = (8..12)
+            .map(|h| TimeBlock::new(at(h, 0), at(h + 1, 0), h as u8 % 3, format!("block {}", h)))
+            .collect::<Vec<_>>();
+        check(json.save_day_timeblocks(day, &blocks[..2]).await);
+        check(json.save_day_timeblocks(next_day, &blocks[2..]).await);
+
+        // A broken day fails the import and leaves no database behind.
+        let broken = dir.path().join("timeblocks").join("2024-06-14.json");
+        std::fs::write(&broken, "[{").unwrap();
+        assert!(SqliteStorage::open(dir.path()).await.is_err());
+        assert!(!dir.path().join(DB_FILE).exists());
+
+        std::fs::remove_file(&broken).unwrap();
+        let sqlite = check(SqliteStorage::open(dir.path()).await);
+        assert!(!dir.path().join(IMPORTING_FILE).exists());
+        assert_eq!(dump(&sqlite).await, dump(&json).await);
+        assert_eq!(check(sqlite.list_days().await), [day, next_day]);
+        let found = check(sqlite.find_timeblock_day(blocks[3].id).await);
+        assert_eq!(found, check(json.find_timeblock_day(blocks[3].id).await));
+        assert_eq!(found, Some(next_day));
+        assert_eq!(check(sqlite.find_timeblock_day(Uuid::new_v4()).await), None);
+        drop(sqlite);
+
+        // Once imported, the JSON data is no longer read.
+        check(json.save_day_timeblocks(day, &[]).await);
+        let sqlite = check(SqliteStorage::open(dir.path()).await);
+        assert_eq!(check(sqlite.get_day_timeblocks(day).await), blocks[..2]);
+    }
+}
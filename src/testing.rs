@@ -0,0 +1,40 @@
+//! Helpers shared by the unit tests.
+
+#![allow(clippy::unwrap_used)]
+
+use chrono::{DateTime, Local, TimeZone};
+
+use crate::{
+    blocktype::{BlockType, Color},
+    err::Error,
+    storage::Storage,
+};
+
+/// `hour:min` on the day every test works with.
+pub fn at(hour: u32, min: u32) -> DateTime<Local> {
+    Local
+        .with_ymd_and_hms(2024, 6, 12, hour, min, 0)
+        .single()
+        .unwrap()
+}
+
+/// Unwrap `res`, panicking with the error's message since `Error` has no
+/// `Debug`.
+pub fn check<T>(res: Result<T, Error>) -> T {
+    match res {
+        Ok(value) => value,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Block types 0 to 2, so requests using them pass validation.
+pub async fn save_blocktypes(storage: &dyn Storage) {
+    let blocktypes = (0..3)
+        .map(|id| BlockType {
+            id,
+            name: format!("type {}", id),
+            color: Color { r: 0, g: 0, b: 0 },
+        })
+        .collect::<Vec<_>>();
+    check(storage.save_blocktypes(&blocktypes).await);
+}
@@ -1,42 +1,307 @@
-use std::path::Path;
-
 use chrono::{DateTime, Local, NaiveDate, NaiveTime};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::{
+    blocktype::BlockType,
+    currentblock::CurrentBlock,
     err::{Error, ErrorType},
-    err_from_type, err_with_context,
+    err_from_type,
+    locks::DayLocks,
+    revision,
+    storage::Storage,
+    violations::Violations,
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct TimeBlock {
+    pub id: Uuid,
     pub start_time: DateTime<Local>,
     pub end_time: DateTime<Local>,
     pub block_type_id: u8,
     pub title: String,
 }
 
+/// Titles and block types of the two blocks a split leaves.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SplitHalves {
+    before_title: String,
+    after_title: String,
+    before_block_type_id: u8,
+    after_block_type_id: u8,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SplitTimeBlockQuery {
     start_time: DateTime<Local>,
     end_time: DateTime<Local>,
     split_time: DateTime<Local>,
+    #[serde(flatten)]
+    halves: SplitHalves,
+}
+
+/// A point to split a block at and the segment that follows it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SplitPoint {
+    split_time: DateTime<Local>,
+    title: String,
+    block_type_id: u8,
+}
+
+/// Split a block into several segments at once. The segment before the first
+/// split point takes the `before_` title and block type.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MultiSplitTimeBlockQuery {
+    start_time: DateTime<Local>,
+    end_time: DateTime<Local>,
     before_title: String,
-    after_title: String,
     before_block_type_id: u8,
-    after_block_type_id: u8,
+    splits: Vec<SplitPoint>,
+}
+
+/// Where an adjusted block moves to and what it becomes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Adjustment {
+    new_start_time: DateTime<Local>,
+    new_end_time: DateTime<Local>,
+    title: String,
+    block_type_id: u8,
+    /// Remove neighbours the new range covers instead of refusing.
+    #[serde(default)]
+    cascade: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AdjustTimeBlockQuery {
     start_time: DateTime<Local>,
     end_time: DateTime<Local>,
-    new_start_time: DateTime<Local>,
-    new_end_time: DateTime<Local>,
+    #[serde(flatten)]
+    adjustment: Adjustment,
+}
+
+/// Queries naming a block by id take the day it is recorded on as an
+/// optional hint; without it the day is looked up.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SplitTimeBlockByIdQuery {
+    #[serde(default)]
+    day: Option<NaiveDate>,
+    id: Uuid,
+    split_time: DateTime<Local>,
+    #[serde(flatten)]
+    halves: SplitHalves,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdjustTimeBlockByIdQuery {
+    #[serde(default)]
+    day: Option<NaiveDate>,
+    id: Uuid,
+    #[serde(flatten)]
+    adjustment: Adjustment,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpdateTimeBlockQuery {
+    #[serde(default)]
+    day: Option<NaiveDate>,
+    id: Uuid,
     title: String,
     block_type_id: u8,
 }
 
+/// What happens to the time a deleted block covered.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GapPolicy {
+    /// Leave a gap in the day.
+    #[default]
+    Leave,
+    /// Extend the previous block to the deleted block's end.
+    ExtendPrevious,
+    /// Extend the next block back to the deleted block's start.
+    ExtendNext,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeleteTimeBlockQuery {
+    #[serde(default)]
+    day: Option<NaiveDate>,
+    id: Uuid,
+    #[serde(default)]
+    gap: GapPolicy,
+}
+
+/// How an inserted block is reconciled with blocks already covering its range.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// Refuse the insertion if anything overlaps.
+    #[default]
+    Reject,
+    /// Cut existing blocks back so the new block fits in whole.
+    TrimExisting,
+    /// Keep existing blocks and insert only into the free time of the range.
+    TrimNew,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InsertTimeBlockQuery {
+    start_time: DateTime<Local>,
+    end_time: DateTime<Local>,
+    block_type_id: u8,
+    title: String,
+    #[serde(default)]
+    overlap: OverlapPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MergeTimeBlocksQuery {
+    #[serde(default)]
+    day: Option<NaiveDate>,
+    first_id: Uuid,
+    second_id: Uuid,
+    title: String,
+    block_type_id: u8,
+    /// Merge blocks with a gap between them, the gap becoming part of the
+    /// merged block, instead of refusing.
+    #[serde(default)]
+    absorb_gap: bool,
+}
+
+impl SplitHalves {
+    fn check(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        let mut violations = Violations::default();
+        violations.title("before_title", &self.before_title);
+        violations.title("after_title", &self.after_title);
+        violations.block_type(
+            "before_block_type_id",
+            self.before_block_type_id,
+            blocktypes,
+        );
+        violations.block_type("after_block_type_id", self.after_block_type_id, blocktypes);
+        violations.into_result()
+    }
+
+    fn before(&self) -> (String, u8) {
+        (self.before_title.clone(), self.before_block_type_id)
+    }
+
+    fn after(&self) -> (String, u8) {
+        (self.after_title.clone(), self.after_block_type_id)
+    }
+}
+
+impl MultiSplitTimeBlockQuery {
+    fn check(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        let mut violations = Violations::default();
+        violations.title("before_title", &self.before_title);
+        violations.block_type(
+            "before_block_type_id",
+            self.before_block_type_id,
+            blocktypes,
+        );
+        if self.splits.is_empty() {
+            violations.push("splits", "must not be empty".to_string());
+        }
+        for (i, split) in self.splits.iter().enumerate() {
+            violations.title(&format!("splits[{}].title", i), &split.title);
+            violations.block_type(
+                &format!("splits[{}].block_type_id", i),
+                split.block_type_id,
+                blocktypes,
+            );
+            if i > 0 {
+                violations.time_order(
+                    (
+                        &format!("splits[{}].split_time", i - 1),
+                        self.splits[i - 1].split_time,
+                    ),
+                    (&format!("splits[{}].split_time", i), split.split_time),
+                );
+            }
+        }
+        violations.into_result()
+    }
+}
+
+impl Adjustment {
+    fn check(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        let mut violations = Violations::default();
+        violations.time_order(
+            ("new_start_time", self.new_start_time),
+            ("new_end_time", self.new_end_time),
+        );
+        violations.title("title", &self.title);
+        violations.block_type("block_type_id", self.block_type_id, blocktypes);
+        violations.into_result()
+    }
+}
+
+impl UpdateTimeBlockQuery {
+    fn check(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        let mut violations = Violations::default();
+        violations.title("title", &self.title);
+        violations.block_type("block_type_id", self.block_type_id, blocktypes);
+        violations.into_result()
+    }
+}
+
+impl InsertTimeBlockQuery {
+    fn check(&self, blocktypes: &[BlockType], now: DateTime<Local>) -> Result<(), Error> {
+        let mut violations = Violations::default();
+        violations.time_order(("start_time", self.start_time), ("end_time", self.end_time));
+        // The running block starts where the last recorded one ends, so a
+        // block ending later would make the next one end before it starts.
+        if self.end_time > now {
+            violations.push("end_time", "must not be in the future".to_string());
+        }
+        violations.title("title", &self.title);
+        violations.block_type("block_type_id", self.block_type_id, blocktypes);
+        violations.into_result()
+    }
+}
+
+impl MergeTimeBlocksQuery {
+    fn check(&self, blocktypes: &[BlockType]) -> Result<(), Error> {
+        let mut violations = Violations::default();
+        violations.title("title", &self.title);
+        violations.block_type("block_type_id", self.block_type_id, blocktypes);
+        violations.into_result()
+    }
+}
+
+/// First instant of `day`, the start of a block continued from the day before.
+pub fn day_start(day: NaiveDate) -> Result<DateTime<Local>, Error> {
+    day.and_time(NaiveTime::from_hms_opt(0, 0, 0).ok_or(err_from_type!(
+        ErrorType::Chrono,
+        "Creating start time for {}",
+        day.format("%Y-%m-%d")
+    ))?)
+    .and_local_timezone(Local)
+    .single()
+    .ok_or(err_from_type!(
+        ErrorType::Chrono,
+        "No single time identifiable for start time for {}",
+        day.format("%Y-%m-%d")
+    ))
+}
+
+/// 23:59:59 of `day`, where a block running past midnight is cut.
+pub fn day_end(day: NaiveDate) -> Result<DateTime<Local>, Error> {
+    day.and_time(NaiveTime::from_hms_opt(23, 59, 59).ok_or(err_from_type!(
+        ErrorType::Chrono,
+        "Creating end time for {}",
+        day.format("%Y-%m-%d")
+    ))?)
+    .and_local_timezone(Local)
+    .single()
+    .ok_or(err_from_type!(
+        ErrorType::Chrono,
+        "No single time identifiable for end time for {}",
+        day.format("%Y-%m-%d")
+    ))
+}
+
 impl TimeBlock {
     pub fn new(
         start_time: DateTime<Local>,
@@ -45,6 +310,7 @@ impl TimeBlock {
         title: String,
     ) -> TimeBlock {
         TimeBlock {
+            id: Uuid::new_v4(),
             start_time,
             end_time,
             block_type_id,
@@ -57,222 +323,1365 @@ impl TimeBlock {
     }
 
     pub async fn get_day_timeblocks(
-        data_dir: &Path,
+        storage: &dyn Storage,
         day: NaiveDate,
     ) -> Result<Vec<TimeBlock>, Error> {
-        let time_blocks_dir = data_dir.join("timeblocks");
-        if !time_blocks_dir.exists() {
-            tokio::fs::create_dir(&time_blocks_dir)
-                .await
-                .map_err(|e| err_with_context!(e, "Creating timeblocks directory"))?;
-        }
-
-        let file_name = time_blocks_dir.join(format!("{}.json", day.format("%Y-%m-%d")));
-        let file = tokio::fs::File::open(&file_name).await;
-        if let Err(e) = &file {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                return Ok(vec![]);
-            }
-        }
-        let mut file = file.map_err(|e| err_with_context!(e, "Opening {}", file_name.display()))?;
-        let mut content = String::new();
-        tokio::io::AsyncReadExt::read_to_string(&mut file, &mut content)
-            .await
-            .map_err(|e| err_with_context!(e, "Reading {}", file_name.display()))?;
-        if content.is_empty() {
-            return Ok(vec![]);
-        }
-        let timeblocks: Vec<TimeBlock> = serde_json::from_str(&content)
-            .map_err(|e| err_with_context!(e, "Deserializing {}", file_name.display()))?;
-        Ok(timeblocks)
+        storage.get_day_timeblocks(day).await
     }
 
-    pub async fn save(&self, data_dir: &Path) -> Result<(), Error> {
+    /// Append to the day files. Callers must hold the locks of the start and
+    /// end day.
+    async fn save(&self, storage: &dyn Storage) -> Result<(), Error> {
         // Save to the end time day file.
         // If the day changed, find previous day records. If they exist, split the block in two and save them.
         let day = self.end_time.date_naive();
         let start_day = self.start_time.date_naive();
         let mut self_clone = self.clone();
         if day != start_day {
-            let mut timeblocks = TimeBlock::get_day_timeblocks(data_dir, start_day)
-                .await
-                .unwrap_or_default();
+            let mut timeblocks = TimeBlock::get_day_timeblocks(storage, start_day).await?;
             // End at 11:59:59 of the start day
-            let end_time = start_day
-                .and_time(NaiveTime::from_hms_opt(23, 59, 59).ok_or(err_from_type!(
-                    ErrorType::Chrono,
-                    "Creating end time for {}",
-                    start_day.format("%Y-%m-%d")
-                ))?)
-                .and_local_timezone(Local)
-                .single()
-                .ok_or(err_from_type!(
-                    ErrorType::Chrono,
-                    "No single time identifiable for end time for {}",
-                    start_day.format("%Y-%m-%d")
-                ))?;
+            let end_time = day_end(start_day)?;
             timeblocks.push(TimeBlock::new(
                 self.start_time,
                 end_time,
                 self.block_type_id,
                 self.title.clone(),
             ));
-            //let file_name = format!("timeblocks/{}.json", start_day.format("%Y-%m-%d"));
-            let file_name = data_dir
-                .join("timeblocks")
-                .join(format!("{}.json", start_day.format("%Y-%m-%d")));
-            let content = serde_json::to_string_pretty(&timeblocks)
-                .map_err(|e| err_with_context!(e, "Serializing {}", file_name.display()))?;
-            tokio::fs::write(file_name, content).await.map_err(|e| {
-                err_with_context!(
-                    e,
-                    "Writing timeblocks/{}.json",
-                    start_day.format("%Y-%m-%d")
-                )
-            })?;
-            self_clone.start_time = day
-                .and_time(NaiveTime::from_hms_opt(0, 0, 0).ok_or(err_from_type!(
-                    ErrorType::Chrono,
-                    "Creating start time for {}",
-                    day.format("%Y-%m-%d")
-                ))?)
-                .and_local_timezone(Local)
-                .single()
-                .ok_or(err_from_type!(
-                    ErrorType::Chrono,
-                    "No single time identifiable for start time for {}",
-                    day.format("%Y-%m-%d")
-                ))?;
+            storage.save_day_timeblocks(start_day, &timeblocks).await?;
+            self_clone.start_time = day_start(day)?;
         }
-        let mut timeblocks = TimeBlock::get_day_timeblocks(data_dir, day)
-            .await
-            .unwrap_or_default();
+        let mut timeblocks = TimeBlock::get_day_timeblocks(storage, day).await?;
         timeblocks.push(self_clone);
-        let file_name = data_dir
-            .join("timeblocks")
-            .join(format!("{}.json", day.format("%Y-%m-%d")));
-        let contents = serde_json::to_string_pretty(&timeblocks)
-            .map_err(|e| err_with_context!(e, "Serializing {}", file_name.display()))?;
-        tokio::fs::write(&file_name, contents)
-            .await
-            .map_err(|e| err_with_context!(e, "Writing timeblocks {}", file_name.display()))?;
-        Ok(())
+        storage.save_day_timeblocks(day, &timeblocks).await
     }
 
-    pub async fn split_timeblock(
-        data_dir: &Path,
-        split_time_block_query: SplitTimeBlockQuery,
-    ) -> Result<(), Error> {
-        let day = split_time_block_query.start_time.date_naive();
-        let mut timeblocks = TimeBlock::get_day_timeblocks(data_dir, day).await?;
-        let target_block = timeblocks
+    /// When the running block started: the end of the last block recorded
+    /// `today`, else of the day before, else midnight.
+    pub async fn running_since(
+        storage: &dyn Storage,
+        today: NaiveDate,
+        today_blocks: &[TimeBlock],
+    ) -> Result<DateTime<Local>, Error> {
+        if let Some(last) = today_blocks.last() {
+            return Ok(last.end_time);
+        }
+        let yesterday = today - chrono::Duration::days(1);
+        match TimeBlock::get_day_timeblocks(storage, yesterday)
+            .await?
+            .last()
+        {
+            Some(last) => Ok(last.end_time),
+            None => day_start(today),
+        }
+    }
+
+    /// Close the running block at `now` with the stored current block's type
+    /// and title, then make `new_current_block` the current block.
+    /// Returns the new revision of today.
+    pub async fn next_timeblock(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        now: DateTime<Local>,
+        new_current_block: CurrentBlock,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        new_current_block.check(&BlockType::load(storage).await?)?;
+        let today = now.date_naive();
+        let yesterday = today - chrono::Duration::days(1);
+        let _guard = locks.lock(&[yesterday, today]).await?;
+
+        let time_blocks = TimeBlock::get_day_timeblocks(storage, today).await?;
+        revision::check(if_match, &time_blocks)?;
+        let current_data = CurrentBlock::get(storage).await?;
+        let start_time = TimeBlock::running_since(storage, today, &time_blocks).await?;
+        let timeblock = TimeBlock::new(
+            start_time,
+            now,
+            current_data.block_type_id,
+            current_data.current_block_name,
+        );
+        timeblock.save(storage).await?;
+        new_current_block.save(storage).await?;
+        revision::of(&TimeBlock::get_day_timeblocks(storage, today).await?)
+    }
+
+    /// Lock `day`, check `if_match` against its revision, let `modify` change
+    /// its time blocks and save them. Returns the new revision of `day`.
+    async fn modify_day<F>(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        day: NaiveDate,
+        if_match: Option<&str>,
+        modify: F,
+    ) -> Result<String, Error>
+    where
+        F: FnOnce(&mut Vec<TimeBlock>) -> Result<(), Error>,
+    {
+        let _guard = locks.lock(&[day]).await?;
+        let mut timeblocks = TimeBlock::get_day_timeblocks(storage, day).await?;
+        revision::check(if_match, &timeblocks)?;
+        modify(&mut timeblocks)?;
+        storage.save_day_timeblocks(day, &timeblocks).await?;
+        revision::of(&timeblocks)
+    }
+
+    fn position_by_range(
+        timeblocks: &[TimeBlock],
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+    ) -> Result<usize, Error> {
+        timeblocks
             .iter()
-            .find(|b| {
-                b.start_time == split_time_block_query.start_time
-                    && b.end_time == split_time_block_query.end_time
-            })
+            .position(|b| b.start_time == start_time && b.end_time == end_time)
             .ok_or(err_from_type!(
                 ErrorType::NotFound,
                 "Time block not found from {} to {}",
-                split_time_block_query
-                    .start_time
-                    .format("%Y-%m-%d %H:%M:%S"),
-                split_time_block_query.end_time.format("%Y-%m-%d %H:%M:%S")
-            ))?;
-        let block_idx = timeblocks
+                start_time.format("%Y-%m-%d %H:%M:%S"),
+                end_time.format("%Y-%m-%d %H:%M:%S")
+            ))
+    }
+
+    /// The day block `id` is recorded on: `hint` if given, else looked up.
+    async fn day_of(
+        storage: &dyn Storage,
+        hint: Option<NaiveDate>,
+        id: Uuid,
+    ) -> Result<NaiveDate, Error> {
+        match hint {
+            Some(day) => Ok(day),
+            None => storage.find_timeblock_day(id).await?.ok_or(err_from_type!(
+                ErrorType::NotFound,
+                "Time block {} not found",
+                id
+            )),
+        }
+    }
+
+    fn position_by_id(timeblocks: &[TimeBlock], id: Uuid) -> Result<usize, Error> {
+        timeblocks
             .iter()
-            .position(|b| b == target_block)
+            .position(|b| b.id == id)
             .ok_or(err_from_type!(
-                ErrorType::InternalRustError,
-                "Time block not found from {} to {} after finding it",
-                split_time_block_query
-                    .start_time
-                    .format("%Y-%m-%d %H:%M:%S"),
-                split_time_block_query.end_time.format("%Y-%m-%d %H:%M:%S")
-            ))?;
-        let before_block = TimeBlock::new(
-            split_time_block_query.start_time,
-            split_time_block_query.split_time,
-            split_time_block_query.before_block_type_id,
-            split_time_block_query.before_title,
-        );
+                ErrorType::NotFound,
+                "Time block {} not found",
+                id
+            ))
+    }
+
+    /// Replace the block at `block_idx` with two blocks meeting at
+    /// `split_time`, which must lie inside it. The first keeps the original id.
+    fn split_at(
+        timeblocks: &mut Vec<TimeBlock>,
+        block_idx: usize,
+        split_time: DateTime<Local>,
+        (before_title, before_block_type_id): (String, u8),
+        (after_title, after_block_type_id): (String, u8),
+    ) -> Result<(), Error> {
+        let target_block = &timeblocks[block_idx];
+        if split_time <= target_block.start_time || split_time >= target_block.end_time {
+            let mut violations = Violations::default();
+            violations.push(
+                "split_time",
+                format!(
+                    "must lie strictly between {} and {}",
+                    target_block.start_time.format("%Y-%m-%d %H:%M:%S"),
+                    target_block.end_time.format("%Y-%m-%d %H:%M:%S")
+                ),
+            );
+            return violations.into_result();
+        }
+        let target_block = timeblocks.remove(block_idx);
+        let before_block = TimeBlock {
+            id: target_block.id,
+            ..TimeBlock::new(
+                target_block.start_time,
+                split_time,
+                before_block_type_id,
+                before_title,
+            )
+        };
         let after_block = TimeBlock::new(
-            split_time_block_query.split_time,
-            split_time_block_query.end_time,
-            split_time_block_query.after_block_type_id,
-            split_time_block_query.after_title,
+            split_time,
+            target_block.end_time,
+            after_block_type_id,
+            after_title,
         );
-
-        timeblocks.remove(block_idx);
         timeblocks.insert(block_idx, before_block);
         timeblocks.insert(block_idx + 1, after_block);
+        Ok(())
+    }
+
+    /// Replace the blocks at `first_idx` and `first_idx + 1` with one block
+    /// spanning both. The merged block keeps the first block's id.
+    fn merge_at(
+        timeblocks: &mut Vec<TimeBlock>,
+        first_idx: usize,
+        title: String,
+        block_type_id: u8,
+    ) {
+        let second_block = timeblocks.remove(first_idx + 1);
+        let first_block = &mut timeblocks[first_idx];
+        first_block.end_time = second_block.end_time;
+        first_block.title = title;
+        first_block.block_type_id = block_type_id;
+    }
 
-        let file_name = data_dir
-            .join("timeblocks")
-            .join(format!("{}.json", day.format("%Y-%m-%d")));
-        let content = serde_json::to_string_pretty(&timeblocks)
-            .map_err(|e| err_with_context!(e, "Serializing {}", file_name.display()))?;
-        tokio::fs::write(&file_name, content)
-            .await
-            .map_err(|e| err_with_context!(e, "Writing {}", file_name.display()))?;
+    /// Remove the block at `block_idx`, closing the gap according to `gap`.
+    fn delete_at(
+        timeblocks: &mut Vec<TimeBlock>,
+        block_idx: usize,
+        gap: GapPolicy,
+    ) -> Result<(), Error> {
+        let (id, start_time, end_time) = {
+            let deleted = &timeblocks[block_idx];
+            (deleted.id, deleted.start_time, deleted.end_time)
+        };
+        match gap {
+            GapPolicy::Leave => {}
+            GapPolicy::ExtendPrevious => {
+                let pre_block = block_idx
+                    .checked_sub(1)
+                    .and_then(|idx| timeblocks.get_mut(idx))
+                    .ok_or(err_from_type!(
+                        ErrorType::NoNeighbour,
+                        "No time block before {} to extend",
+                        id
+                    ))?;
+                pre_block.end_time = end_time;
+            }
+            GapPolicy::ExtendNext => {
+                let post_block = timeblocks.get_mut(block_idx + 1).ok_or(err_from_type!(
+                    ErrorType::NoNeighbour,
+                    "No time block after {} to extend",
+                    id
+                ))?;
+                post_block.start_time = start_time;
+            }
+        }
+        timeblocks.remove(block_idx);
         Ok(())
     }
 
-    pub async fn adjust_timeblock(
-        data_dir: &Path,
-        adjust_time_block_query: AdjustTimeBlockQuery,
+    fn overlaps(&self, start_time: DateTime<Local>, end_time: DateTime<Local>) -> bool {
+        self.start_time < end_time && start_time < self.end_time
+    }
+
+    /// Fit `new_block` into a day's blocks according to `overlap`, keeping the
+    /// day ordered by start time.
+    fn insert_into(
+        timeblocks: &mut Vec<TimeBlock>,
+        new_block: TimeBlock,
+        overlap: OverlapPolicy,
     ) -> Result<(), Error> {
-        let day = adjust_time_block_query.start_time.date_naive();
-        let mut timeblocks = TimeBlock::get_day_timeblocks(data_dir, day).await?;
-        let target_block = timeblocks
-            .iter()
-            .find(|b| {
-                b.start_time == adjust_time_block_query.start_time
-                    && b.end_time == adjust_time_block_query.end_time
-            })
-            .ok_or(err_from_type!(
-                ErrorType::NotFound,
-                "Time block not found from {} to {}",
-                adjust_time_block_query
-                    .start_time
-                    .format("%Y-%m-%d %H:%M:%S"),
-                adjust_time_block_query.end_time.format("%Y-%m-%d %H:%M:%S")
-            ))?;
-        let block_idx = timeblocks
+        let (start_time, end_time) = (new_block.start_time, new_block.end_time);
+        let new_blocks = match overlap {
+            OverlapPolicy::Reject => {
+                if let Some(block) = timeblocks.iter().find(|b| b.overlaps(start_time, end_time)) {
+                    return Err(err_from_type!(
+                        ErrorType::Overlap,
+                        "Time block overlaps {} from {} to {}",
+                        block.id,
+                        block.start_time.format("%Y-%m-%d %H:%M:%S"),
+                        block.end_time.format("%Y-%m-%d %H:%M:%S")
+                    ));
+                }
+                vec![new_block]
+            }
+            OverlapPolicy::TrimExisting => {
+                let mut trimmed = Vec::with_capacity(timeblocks.len() + 1);
+                for block in timeblocks.drain(..) {
+                    if !block.overlaps(start_time, end_time) {
+                        trimmed.push(block);
+                        continue;
+                    }
+                    if block.start_time < start_time {
+                        trimmed.push(TimeBlock {
+                            end_time: start_time,
+                            ..block.clone()
+                        });
+                    }
+                    if block.end_time > end_time {
+                        // Only a block spanning the whole range needs a new id.
+                        let id = if block.start_time < start_time {
+                            Uuid::new_v4()
+                        } else {
+                            block.id
+                        };
+                        trimmed.push(TimeBlock {
+                            id,
+                            start_time: end_time,
+                            ..block
+                        });
+                    }
+                }
+                *timeblocks = trimmed;
+                vec![new_block]
+            }
+            OverlapPolicy::TrimNew => {
+                let mut covered = timeblocks
+                    .iter()
+                    .filter(|b| b.overlaps(start_time, end_time))
+                    .map(|b| (b.start_time, b.end_time))
+                    .collect::<Vec<_>>();
+                covered.sort();
+                let mut gaps = Vec::new();
+                let mut cursor = start_time;
+                for (covered_start, covered_end) in covered {
+                    if covered_start > cursor {
+                        gaps.push((cursor, covered_start));
+                    }
+                    cursor = cursor.max(covered_end);
+                }
+                if cursor < end_time {
+                    gaps.push((cursor, end_time));
+                }
+                if gaps.is_empty() {
+                    return Err(err_from_type!(
+                        ErrorType::Overlap,
+                        "No free time from {} to {}",
+                        start_time.format("%Y-%m-%d %H:%M:%S"),
+                        end_time.format("%Y-%m-%d %H:%M:%S")
+                    ));
+                }
+                gaps.into_iter()
+                    .map(|(gap_start, gap_end)| {
+                        TimeBlock::new(
+                            gap_start,
+                            gap_end,
+                            new_block.block_type_id,
+                            new_block.title.clone(),
+                        )
+                    })
+                    .collect()
+            }
+        };
+        for block in new_blocks {
+            let idx = timeblocks.partition_point(|b| b.start_time <= block.start_time);
+            timeblocks.insert(idx, block);
+        }
+        Ok(())
+    }
+
+    /// Move the boundaries of the blocks around `block_idx` to meet its
+    /// range. A neighbour the range covers entirely is an error unless
+    /// `cascade` is set, in which case it is removed and the next block along
+    /// is moved instead. With `close_gaps` neighbours that do not reach the
+    /// block are extended to meet it, otherwise only overlapping ones move.
+    fn fit_neighbours(
+        timeblocks: &mut Vec<TimeBlock>,
+        mut block_idx: usize,
+        cascade: bool,
+        close_gaps: bool,
+    ) -> Result<(), Error> {
+        let (id, start_time, end_time) = {
+            let block = &timeblocks[block_idx];
+            (block.id, block.start_time, block.end_time)
+        };
+        while let Some(pre_idx) = block_idx.checked_sub(1) {
+            let pre_block = &timeblocks[pre_idx];
+            if pre_block.end_time <= start_time && !close_gaps {
+                break;
+            }
+            if pre_block.start_time < start_time {
+                timeblocks[pre_idx].end_time = start_time;
+                break;
+            }
+            if !cascade {
+                return Err(if pre_block.start_time == start_time {
+                    err_from_type!(
+                        ErrorType::SwallowsNeighbour,
+                        "Time block {} would cover all of {}",
+                        id,
+                        pre_block.id
+                    )
+                } else {
+                    err_from_type!(
+                        ErrorType::NegativeNeighbour,
+                        "Time block {} would end before it starts at {}",
+                        pre_block.id,
+                        start_time.format("%Y-%m-%d %H:%M:%S")
+                    )
+                });
+            }
+            timeblocks.remove(pre_idx);
+            block_idx = pre_idx;
+        }
+        while let Some(post_block) = timeblocks.get(block_idx + 1) {
+            if post_block.start_time >= end_time && !close_gaps {
+                break;
+            }
+            if post_block.end_time > end_time {
+                timeblocks[block_idx + 1].start_time = end_time;
+                break;
+            }
+            if !cascade {
+                return Err(if post_block.end_time == end_time {
+                    err_from_type!(
+                        ErrorType::SwallowsNeighbour,
+                        "Time block {} would cover all of {}",
+                        id,
+                        post_block.id
+                    )
+                } else {
+                    err_from_type!(
+                        ErrorType::NegativeNeighbour,
+                        "Time block {} would start after it ends at {}",
+                        post_block.id,
+                        end_time.format("%Y-%m-%d %H:%M:%S")
+                    )
+                });
+            }
+            timeblocks.remove(block_idx + 1);
+        }
+        Ok(())
+    }
+
+    /// Move a block of `day`, found by `locate`, to a new range, dragging the
+    /// neighbouring boundaries along. A range running past midnight is cut
+    /// into one block per day like `save` does, the other days' pieces
+    /// trimming the blocks they overlap. Returns the new revision of `day`.
+    async fn adjust_in_days<F>(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        day: NaiveDate,
+        if_match: Option<&str>,
+        locate: F,
+        adjustment: Adjustment,
+    ) -> Result<String, Error>
+    where
+        F: Fn(&[TimeBlock]) -> Result<usize, Error>,
+    {
+        let Adjustment {
+            new_start_time,
+            new_end_time,
+            title,
+            block_type_id,
+            cascade,
+        } = adjustment;
+        // The part of the new range that falls on `day`.
+        let piece = |day: NaiveDate| -> Result<_, Error> {
+            Ok((
+                new_start_time.max(day_start(day)?),
+                new_end_time.min(day_end(day)?),
+            ))
+        };
+        let (start_time, end_time) = piece(day)?;
+        if start_time >= end_time {
+            return Err(err_from_type!(
+                ErrorType::InvalidTimeRange,
+                "New range from {} to {} leaves nothing on {}",
+                new_start_time.format("%Y-%m-%d %H:%M:%S"),
+                new_end_time.format("%Y-%m-%d %H:%M:%S"),
+                day.format("%Y-%m-%d")
+            ));
+        }
+        let days = new_start_time
+            .date_naive()
+            .iter_days()
+            .take_while(|d| *d <= new_end_time.date_naive())
+            .collect::<Vec<_>>();
+        let _guard = locks.lock(&days).await?;
+
+        // Apply to every day before writing any, so a rejection leaves all
+        // days untouched.
+        let mut updated = Vec::with_capacity(days.len());
+        for other_day in days {
+            let mut timeblocks = TimeBlock::get_day_timeblocks(storage, other_day).await?;
+            if other_day == day {
+                revision::check(if_match, &timeblocks)?;
+                let block_idx = locate(&timeblocks)?;
+                let block = &mut timeblocks[block_idx];
+                block.start_time = start_time;
+                block.end_time = end_time;
+                block.title = title.clone();
+                block.block_type_id = block_type_id;
+                TimeBlock::fit_neighbours(&mut timeblocks, block_idx, cascade, true)?;
+            } else {
+                let (start_time, end_time) = piece(other_day)?;
+                if start_time < end_time {
+                    let block_idx = timeblocks.partition_point(|b| b.start_time < start_time);
+                    timeblocks.insert(
+                        block_idx,
+                        TimeBlock::new(start_time, end_time, block_type_id, title.clone()),
+                    );
+                    TimeBlock::fit_neighbours(&mut timeblocks, block_idx, cascade, false)?;
+                }
+            }
+            updated.push((other_day, timeblocks));
+        }
+        for (other_day, timeblocks) in &updated {
+            storage.save_day_timeblocks(*other_day, timeblocks).await?;
+        }
+        let (_, timeblocks) = updated
             .iter()
-            .position(|b| b == target_block)
+            .find(|(other_day, _)| *other_day == day)
             .ok_or(err_from_type!(
                 ErrorType::InternalRustError,
-                "Time block not found from {} to {} after finding it",
-                adjust_time_block_query
-                    .start_time
-                    .format("%Y-%m-%d %H:%M:%S"),
-                adjust_time_block_query.end_time.format("%Y-%m-%d %H:%M:%S")
+                "Adjusted day {} not among locked days",
+                day.format("%Y-%m-%d")
             ))?;
+        revision::of(timeblocks)
+    }
+
+    pub async fn split_timeblock(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        split_time_block_query: SplitTimeBlockQuery,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        split_time_block_query
+            .halves
+            .check(&BlockType::load(storage).await?)?;
+        let day = split_time_block_query.start_time.date_naive();
+        TimeBlock::modify_day(storage, locks, day, if_match, |timeblocks| {
+            let block_idx = TimeBlock::position_by_range(
+                timeblocks,
+                split_time_block_query.start_time,
+                split_time_block_query.end_time,
+            )?;
+            TimeBlock::split_at(
+                timeblocks,
+                block_idx,
+                split_time_block_query.split_time,
+                split_time_block_query.halves.before(),
+                split_time_block_query.halves.after(),
+            )
+        })
+        .await
+    }
+
+    /// Split a block at every point of `query.splits` in one go. Either all
+    /// splits apply or none do.
+    pub async fn split_timeblock_multi(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        query: MultiSplitTimeBlockQuery,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        query.check(&BlockType::load(storage).await?)?;
+        let day = query.start_time.date_naive();
+        TimeBlock::modify_day(storage, locks, day, if_match, |timeblocks| {
+            let first_idx =
+                TimeBlock::position_by_range(timeblocks, query.start_time, query.end_time)?;
+            let mut before = (query.before_title, query.before_block_type_id);
+            for (block_idx, split) in (first_idx..).zip(query.splits) {
+                let after = (split.title, split.block_type_id);
+                TimeBlock::split_at(
+                    timeblocks,
+                    block_idx,
+                    split.split_time,
+                    before,
+                    after.clone(),
+                )?;
+                before = after;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn split_timeblock_by_id(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        query: SplitTimeBlockByIdQuery,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        query.halves.check(&BlockType::load(storage).await?)?;
+        let day = TimeBlock::day_of(storage, query.day, query.id).await?;
+        TimeBlock::modify_day(storage, locks, day, if_match, |timeblocks| {
+            let block_idx = TimeBlock::position_by_id(timeblocks, query.id)?;
+            TimeBlock::split_at(
+                timeblocks,
+                block_idx,
+                query.split_time,
+                query.halves.before(),
+                query.halves.after(),
+            )
+        })
+        .await
+    }
+
+    pub async fn adjust_timeblock(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        adjust_time_block_query: AdjustTimeBlockQuery,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        adjust_time_block_query
+            .adjustment
+            .check(&BlockType::load(storage).await?)?;
+        let day = adjust_time_block_query.start_time.date_naive();
+        TimeBlock::adjust_in_days(
+            storage,
+            locks,
+            day,
+            if_match,
+            |timeblocks| {
+                TimeBlock::position_by_range(
+                    timeblocks,
+                    adjust_time_block_query.start_time,
+                    adjust_time_block_query.end_time,
+                )
+            },
+            adjust_time_block_query.adjustment,
+        )
+        .await
+    }
+
+    pub async fn adjust_timeblock_by_id(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        query: AdjustTimeBlockByIdQuery,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        query.adjustment.check(&BlockType::load(storage).await?)?;
+        let day = TimeBlock::day_of(storage, query.day, query.id).await?;
+        TimeBlock::adjust_in_days(
+            storage,
+            locks,
+            day,
+            if_match,
+            |timeblocks| TimeBlock::position_by_id(timeblocks, query.id),
+            query.adjustment,
+        )
+        .await
+    }
 
-        if let Some(pre_block) = timeblocks.get_mut(block_idx - 1) {
-            pre_block.end_time = adjust_time_block_query.new_start_time;
+    /// Change the title and block type of a block, leaving its times alone.
+    pub async fn update_timeblock(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        query: UpdateTimeBlockQuery,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        query.check(&BlockType::load(storage).await?)?;
+        let day = TimeBlock::day_of(storage, query.day, query.id).await?;
+        TimeBlock::modify_day(storage, locks, day, if_match, |timeblocks| {
+            let block_idx = TimeBlock::position_by_id(timeblocks, query.id)?;
+            let block = &mut timeblocks[block_idx];
+            block.title = query.title;
+            block.block_type_id = query.block_type_id;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Remove a block, leaving a gap or extending a neighbour over it.
+    pub async fn delete_timeblock(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        query: DeleteTimeBlockQuery,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        let day = TimeBlock::day_of(storage, query.day, query.id).await?;
+        TimeBlock::modify_day(storage, locks, day, if_match, |timeblocks| {
+            let block_idx = TimeBlock::position_by_id(timeblocks, query.id)?;
+            TimeBlock::delete_at(timeblocks, block_idx, query.gap)
+        })
+        .await
+    }
+
+    /// Record a block for a range ending no later than `now`. A range
+    /// running past midnight is cut into one block per day like `save` does.
+    /// `if_match` is checked against the start day, whose new revision is
+    /// returned.
+    pub async fn insert_timeblock(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        now: DateTime<Local>,
+        query: InsertTimeBlockQuery,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        query.check(&BlockType::load(storage).await?, now)?;
+        let start_day = query.start_time.date_naive();
+        let end_day = query.end_time.date_naive();
+        let days = start_day
+            .iter_days()
+            .take_while(|day| *day <= end_day)
+            .collect::<Vec<_>>();
+        let _guard = locks.lock(&days).await?;
+
+        // Apply to every day before writing any, so a rejection leaves all
+        // days untouched.
+        let mut updated = Vec::with_capacity(days.len());
+        for day in days {
+            let mut timeblocks = TimeBlock::get_day_timeblocks(storage, day).await?;
+            if day == start_day {
+                revision::check(if_match, &timeblocks)?;
+            }
+            let start_time = if day == start_day {
+                query.start_time
+            } else {
+                day_start(day)?
+            };
+            let end_time = if day == end_day {
+                query.end_time
+            } else {
+                day_end(day)?
+            };
+            if start_time < end_time {
+                let new_block = TimeBlock::new(
+                    start_time,
+                    end_time,
+                    query.block_type_id,
+                    query.title.clone(),
+                );
+                TimeBlock::insert_into(&mut timeblocks, new_block, query.overlap)?;
+            }
+            updated.push((day, timeblocks));
         }
-        if let Some(post_block) = timeblocks.get_mut(block_idx + 1) {
-            post_block.start_time = adjust_time_block_query.new_end_time;
+        for (day, timeblocks) in &updated {
+            storage.save_day_timeblocks(*day, timeblocks).await?;
         }
-        let new_block = TimeBlock::new(
-            adjust_time_block_query.new_start_time,
-            adjust_time_block_query.new_end_time,
-            adjust_time_block_query.block_type_id,
-            adjust_time_block_query.title,
+        revision::of(&updated[0].1)
+    }
+
+    /// Join two neighbouring blocks of a day into one. Blocks with a gap
+    /// between them are only merged with `absorb_gap`.
+    pub async fn merge_timeblocks(
+        storage: &dyn Storage,
+        locks: &DayLocks,
+        query: MergeTimeBlocksQuery,
+        if_match: Option<&str>,
+    ) -> Result<String, Error> {
+        query.check(&BlockType::load(storage).await?)?;
+        let day = TimeBlock::day_of(storage, query.day, query.first_id).await?;
+        TimeBlock::modify_day(storage, locks, day, if_match, |timeblocks| {
+            let first_idx = TimeBlock::position_by_id(timeblocks, query.first_id)?;
+            let second_idx = TimeBlock::position_by_id(timeblocks, query.second_id)?;
+            if second_idx != first_idx + 1 {
+                return Err(err_from_type!(
+                    ErrorType::NotAdjacent,
+                    "Time block {} does not directly follow {}",
+                    query.second_id,
+                    query.first_id
+                ));
+            }
+            if timeblocks[first_idx].end_time != timeblocks[second_idx].start_time
+                && !query.absorb_gap
+            {
+                return Err(err_from_type!(
+                    ErrorType::NotAdjacent,
+                    "Time block {} ends at {} but {} starts at {}",
+                    query.first_id,
+                    timeblocks[first_idx].end_time.format("%Y-%m-%d %H:%M:%S"),
+                    query.second_id,
+                    timeblocks[second_idx]
+                        .start_time
+                        .format("%Y-%m-%d %H:%M:%S")
+                ));
+            }
+            TimeBlock::merge_at(timeblocks, first_idx, query.title, query.block_type_id);
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        storage::{json::JsonStorage, memory::MemoryStorage},
+        testing::{at, check, save_blocktypes},
+    };
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_mutations_are_not_lost() {
+        let storage = Arc::new(MemoryStorage::default());
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let initial = (0..10)
+            .map(|h| TimeBlock::new(at(h, 0), at(h + 1, 0), 0, format!("block {}", h)))
+            .collect::<Vec<_>>();
+        check(storage.save_day_timeblocks(day, &initial).await);
+        check(
+            storage
+                .save_current_block(&CurrentBlock {
+                    block_type_id: 0,
+                    current_block_name: "next".to_string(),
+                })
+                .await,
         );
-        timeblocks.remove(block_idx);
-        timeblocks.insert(block_idx, new_block);
-
-        let file_name = data_dir
-            .join("timeblocks")
-            .join(format!("{}.json", day.format("%Y-%m-%d")));
-        let content = serde_json::to_string_pretty(&timeblocks)
-            .map_err(|e| err_with_context!(e, "Serializing {}", file_name.display()))?;
-        tokio::fs::write(&file_name, &content)
-            .await
-            .map_err(|e| err_with_context!(e, "Writing {}", file_name.display()))?;
-        Ok(())
+
+        let mut tasks = Vec::new();
+        for h in 0..5 {
+            let (storage, locks) = (storage.clone(), locks.clone());
+            tasks.push(tokio::spawn(async move {
+                let query = SplitTimeBlockQuery {
+                    start_time: at(h, 0),
+                    end_time: at(h + 1, 0),
+                    split_time: at(h, 30),
+                    halves: SplitHalves {
+                        before_title: format!("before {}", h),
+                        after_title: format!("after {}", h),
+                        before_block_type_id: 0,
+                        after_block_type_id: 0,
+                    },
+                };
+                TimeBlock::split_timeblock(&*storage, &locks, query, None).await
+            }));
+        }
+        for h in 5..10 {
+            let (storage, locks) = (storage.clone(), locks.clone());
+            tasks.push(tokio::spawn(async move {
+                let query = AdjustTimeBlockQuery {
+                    start_time: at(h, 0),
+                    end_time: at(h + 1, 0),
+                    adjustment: Adjustment {
+                        new_start_time: at(h, 0),
+                        new_end_time: at(h + 1, 0),
+                        title: format!("adjusted {}", h),
+                        block_type_id: 0,
+                        cascade: false,
+                    },
+                };
+                TimeBlock::adjust_timeblock(&*storage, &locks, query, None).await
+            }));
+        }
+        for m in 0..5 {
+            let (storage, locks) = (storage.clone(), locks.clone());
+            tasks.push(tokio::spawn(async move {
+                let current = CurrentBlock {
+                    block_type_id: 0,
+                    current_block_name: "next".to_string(),
+                };
+                TimeBlock::next_timeblock(&*storage, &locks, at(12, m), current, None).await
+            }));
+        }
+        for task in tasks {
+            check(task.await.unwrap());
+        }
+
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        assert_eq!(timeblocks.len(), 20);
+        for h in 0..5 {
+            assert!(timeblocks
+                .iter()
+                .any(|b| b.title == format!("before {}", h)));
+            assert!(timeblocks.iter().any(|b| b.title == format!("after {}", h)));
+        }
+        for h in 5..10 {
+            assert!(timeblocks
+                .iter()
+                .any(|b| b.title == format!("adjusted {}", h)));
+        }
+        assert_eq!(timeblocks.iter().filter(|b| b.title == "next").count(), 5);
+    }
+
+    #[tokio::test]
+    async fn unreadable_days_are_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = JsonStorage::new(dir.path().to_path_buf());
+        let locks = DayLocks::default();
+        let current = CurrentBlock {
+            block_type_id: 0,
+            current_block_name: "work".to_string(),
+        };
+        save_blocktypes(&storage).await;
+        check(storage.save_current_block(&current).await);
+        let day = at(0, 0).date_naive();
+        check(storage.save_day_timeblocks(day, &[]).await);
+        let day_file = dir.path().join("timeblocks").join("2024-06-12.json");
+        std::fs::write(&day_file, "[{\"truncated").unwrap();
+
+        let block = TimeBlock::new(at(9, 0), at(10, 0), 0, "work".to_string());
+        assert!(block.save(&storage).await.is_err());
+        let res = TimeBlock::next_timeblock(&storage, &locks, at(10, 0), current, None).await;
+        assert!(res.is_err());
+        assert_eq!(std::fs::read_to_string(&day_file).unwrap(), "[{\"truncated");
+    }
+
+    #[tokio::test]
+    async fn stale_revision_is_rejected() {
+        let storage = MemoryStorage::default();
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let initial = vec![TimeBlock::new(at(9, 0), at(10, 0), 0, "work".to_string())];
+        check(storage.save_day_timeblocks(day, &initial).await);
+        let etag = check(revision::of(&initial));
+
+        let split = |title: &str| SplitTimeBlockQuery {
+            start_time: at(9, 0),
+            end_time: at(10, 0),
+            split_time: at(9, 30),
+            halves: SplitHalves {
+                before_title: title.to_string(),
+                after_title: title.to_string(),
+                before_block_type_id: 0,
+                after_block_type_id: 0,
+            },
+        };
+        let new_etag =
+            check(TimeBlock::split_timeblock(&storage, &locks, split("first"), Some(&etag)).await);
+        assert_ne!(etag, new_etag);
+
+        let adjust = AdjustTimeBlockQuery {
+            start_time: at(9, 0),
+            end_time: at(9, 30),
+            adjustment: Adjustment {
+                new_start_time: at(9, 0),
+                new_end_time: at(9, 30),
+                title: "second".to_string(),
+                block_type_id: 0,
+                cascade: false,
+            },
+        };
+        let res = TimeBlock::adjust_timeblock(&storage, &locks, adjust, Some(&etag)).await;
+        assert!(matches!(
+            res.map_err(|e| e.error_type),
+            Err(ErrorType::PreconditionFailed)
+        ));
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        assert!(timeblocks.iter().all(|b| b.title == "first"));
+    }
+
+    #[tokio::test]
+    async fn delete_extends_neighbour_and_merge_joins() {
+        let storage = MemoryStorage::default();
+        save_blocktypes(&storage).await;
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let initial = (8..12)
+            .map(|h| TimeBlock::new(at(h, 0), at(h + 1, 0), 0, format!("block {}", h)))
+            .collect::<Vec<_>>();
+        check(storage.save_day_timeblocks(day, &initial).await);
+
+        let delete = DeleteTimeBlockQuery {
+            day: None,
+            id: initial[1].id,
+            gap: GapPolicy::ExtendPrevious,
+        };
+        check(TimeBlock::delete_timeblock(&storage, &locks, delete, None).await);
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        assert_eq!(timeblocks.len(), 3);
+        assert_eq!(timeblocks[0].end_time, at(10, 0));
+
+        let not_adjacent = MergeTimeBlocksQuery {
+            day: Some(day),
+            first_id: initial[0].id,
+            second_id: initial[3].id,
+            title: "merged".to_string(),
+            block_type_id: 1,
+            absorb_gap: false,
+        };
+        let res = TimeBlock::merge_timeblocks(&storage, &locks, not_adjacent, None).await;
+        assert!(matches!(
+            res.map_err(|e| e.error_type),
+            Err(ErrorType::NotAdjacent)
+        ));
+
+        let merge = MergeTimeBlocksQuery {
+            day: None,
+            first_id: initial[2].id,
+            second_id: initial[3].id,
+            title: "merged".to_string(),
+            block_type_id: 1,
+            absorb_gap: false,
+        };
+        check(TimeBlock::merge_timeblocks(&storage, &locks, merge, None).await);
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        assert_eq!(timeblocks.len(), 2);
+        assert_eq!(timeblocks[1].id, initial[2].id);
+        assert_eq!(timeblocks[1].start_time, at(10, 0));
+        assert_eq!(timeblocks[1].end_time, at(12, 0));
+        assert_eq!(timeblocks[1].title, "merged");
+    }
+
+    #[tokio::test]
+    async fn merge_absorbs_gaps_only_when_asked() {
+        let storage = MemoryStorage::default();
+        save_blocktypes(&storage).await;
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let initial = vec![
+            TimeBlock::new(at(8, 0), at(9, 0), 0, "first".to_string()),
+            TimeBlock::new(at(9, 30), at(10, 0), 0, "second".to_string()),
+        ];
+        check(storage.save_day_timeblocks(day, &initial).await);
+
+        let merge = |absorb_gap| MergeTimeBlocksQuery {
+            day: Some(day),
+            first_id: initial[0].id,
+            second_id: initial[1].id,
+            title: "merged".to_string(),
+            block_type_id: 0,
+            absorb_gap,
+        };
+        let res = TimeBlock::merge_timeblocks(&storage, &locks, merge(false), None).await;
+        assert!(matches!(
+            res.map_err(|e| e.error_type),
+            Err(ErrorType::NotAdjacent)
+        ));
+        assert_eq!(check(storage.get_day_timeblocks(day).await), initial);
+
+        check(TimeBlock::merge_timeblocks(&storage, &locks, merge(true), None).await);
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        assert_eq!(timeblocks.len(), 1);
+        assert_eq!(timeblocks[0].start_time, at(8, 0));
+        assert_eq!(timeblocks[0].end_time, at(10, 0));
+    }
+
+    #[tokio::test]
+    async fn insert_handles_overlaps_and_midnight() {
+        let storage = MemoryStorage::default();
+        save_blocktypes(&storage).await;
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let next_day = day.succ_opt().unwrap();
+        let existing = TimeBlock::new(at(20, 0), at(23, 0), 0, "evening".to_string());
+        check(
+            storage
+                .save_day_timeblocks(day, std::slice::from_ref(&existing))
+                .await,
+        );
+
+        let insert = |overlap| InsertTimeBlockQuery {
+            start_time: at(21, 0),
+            end_time: at(22, 0) + chrono::Duration::hours(4),
+            block_type_id: 1,
+            title: "late".to_string(),
+            overlap,
+        };
+        let res = TimeBlock::insert_timeblock(
+            &storage,
+            &locks,
+            Local::now(),
+            insert(OverlapPolicy::Reject),
+            None,
+        )
+        .await;
+        assert!(matches!(
+            res.map_err(|e| e.error_type),
+            Err(ErrorType::Overlap)
+        ));
+        assert!(check(storage.get_day_timeblocks(next_day).await).is_empty());
+
+        check(
+            TimeBlock::insert_timeblock(
+                &storage,
+                &locks,
+                Local::now(),
+                insert(OverlapPolicy::TrimNew),
+                None,
+            )
+            .await,
+        );
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        assert_eq!(timeblocks.len(), 2);
+        assert_eq!(timeblocks[0], existing);
+        assert_eq!(timeblocks[1].start_time, at(23, 0));
+        assert_eq!(timeblocks[1].end_time, check(day_end(day)));
+        let timeblocks = check(storage.get_day_timeblocks(next_day).await);
+        assert_eq!(timeblocks.len(), 1);
+        assert_eq!(timeblocks[0].start_time, check(day_start(next_day)));
+
+        let inner = InsertTimeBlockQuery {
+            start_time: at(20, 30),
+            end_time: at(21, 0),
+            block_type_id: 2,
+            title: "inner".to_string(),
+            overlap: OverlapPolicy::TrimExisting,
+        };
+        check(TimeBlock::insert_timeblock(&storage, &locks, Local::now(), inner, None).await);
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        let titles = timeblocks
+            .iter()
+            .map(|b| b.title.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(titles, ["evening", "inner", "evening", "late"]);
+        assert_eq!(timeblocks[0].id, existing.id);
+        assert_ne!(timeblocks[2].id, existing.id);
+        assert_eq!(timeblocks[2].start_time, at(21, 0));
+    }
+
+    #[tokio::test]
+    async fn insert_rejects_blocks_ending_in_the_future() {
+        let storage = MemoryStorage::default();
+        save_blocktypes(&storage).await;
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let insert = |end_time| InsertTimeBlockQuery {
+            start_time: at(11, 0),
+            end_time,
+            block_type_id: 1,
+            title: "meeting".to_string(),
+            overlap: OverlapPolicy::Reject,
+        };
+
+        let res =
+            TimeBlock::insert_timeblock(&storage, &locks, at(12, 0), insert(at(13, 0)), None).await;
+        match res.map_err(|e| e.error_type) {
+            Err(ErrorType::Validation(details)) => {
+                let fields = details.into_iter().map(|d| d.field).collect::<Vec<_>>();
+                assert_eq!(fields, ["end_time"]);
+            }
+            _ => panic!("expected a validation error"),
+        }
+        assert!(check(storage.get_day_timeblocks(day).await).is_empty());
+
+        check(
+            TimeBlock::insert_timeblock(&storage, &locks, at(12, 0), insert(at(12, 0)), None).await,
+        );
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        let start_time = check(TimeBlock::running_since(&storage, day, &timeblocks).await);
+        assert_eq!(start_time, at(12, 0));
+    }
+
+    #[tokio::test]
+    async fn adjust_enforces_invariants_and_crosses_midnight() {
+        let storage = MemoryStorage::default();
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let next_day = day.succ_opt().unwrap();
+        let initial = [(8, 9), (9, 10), (10, 23)]
+            .map(|(from, to)| TimeBlock::new(at(from, 0), at(to, 0), 0, format!("block {}", from)));
+        check(storage.save_day_timeblocks(day, &initial).await);
+        let morning = TimeBlock::new(
+            check(day_start(next_day)),
+            check(day_start(next_day)) + chrono::Duration::hours(2),
+            0,
+            "morning".to_string(),
+        );
+        check(
+            storage
+                .save_day_timeblocks(next_day, std::slice::from_ref(&morning))
+                .await,
+        );
+
+        let adjust =
+            |block: &TimeBlock, new_start_time, new_end_time, cascade| AdjustTimeBlockByIdQuery {
+                day: None,
+                id: block.id,
+                adjustment: Adjustment {
+                    new_start_time,
+                    new_end_time,
+                    title: block.title.clone(),
+                    block_type_id: block.block_type_id,
+                    cascade,
+                },
+            };
+        let error_of = |res: Result<String, Error>| res.err().map(|e| e.error_type);
+
+        // The first block has no previous neighbour.
+        check(
+            TimeBlock::adjust_timeblock_by_id(
+                &storage,
+                &locks,
+                adjust(&initial[0], at(7, 30), at(9, 0), false),
+                None,
+            )
+            .await,
+        );
+        let res = TimeBlock::adjust_timeblock_by_id(
+            &storage,
+            &locks,
+            adjust(&initial[1], at(10, 0), at(9, 0), false),
+            None,
+        )
+        .await;
+        assert!(matches!(error_of(res), Some(ErrorType::Validation(_))));
+        let res = TimeBlock::adjust_timeblock_by_id(
+            &storage,
+            &locks,
+            adjust(&initial[1], at(7, 30), at(10, 0), false),
+            None,
+        )
+        .await;
+        assert!(matches!(error_of(res), Some(ErrorType::SwallowsNeighbour)));
+        let res = TimeBlock::adjust_timeblock_by_id(
+            &storage,
+            &locks,
+            adjust(&initial[1], at(7, 0), at(10, 0), false),
+            None,
+        )
+        .await;
+        assert!(matches!(error_of(res), Some(ErrorType::NegativeNeighbour)));
+
+        check(
+            TimeBlock::adjust_timeblock_by_id(
+                &storage,
+                &locks,
+                adjust(&initial[1], at(7, 0), at(10, 0), true),
+                None,
+            )
+            .await,
+        );
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        assert_eq!(timeblocks.len(), 2);
+        assert_eq!(timeblocks[0].id, initial[1].id);
+        assert_eq!(timeblocks[0].start_time, at(7, 0));
+
+        let past_midnight = at(23, 0) + chrono::Duration::hours(2);
+        check(
+            TimeBlock::adjust_timeblock_by_id(
+                &storage,
+                &locks,
+                adjust(&initial[2], at(10, 0), past_midnight, false),
+                None,
+            )
+            .await,
+        );
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        assert_eq!(timeblocks[1].end_time, check(day_end(day)));
+        let timeblocks = check(storage.get_day_timeblocks(next_day).await);
+        let spans = timeblocks
+            .iter()
+            .map(|b| (b.title.as_str(), b.start_time, b.end_time))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                ("block 10", check(day_start(next_day)), past_midnight),
+                ("morning", past_midnight, morning.end_time),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_split_is_rejected_with_details() {
+        let storage = MemoryStorage::default();
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let initial = vec![TimeBlock::new(at(9, 0), at(10, 0), 0, "work".to_string())];
+        check(storage.save_day_timeblocks(day, &initial).await);
+
+        let split = |split_time, after_title: &str, after_block_type_id| SplitTimeBlockByIdQuery {
+            day: None,
+            id: initial[0].id,
+            split_time,
+            halves: SplitHalves {
+                before_title: "before".to_string(),
+                after_title: after_title.to_string(),
+                before_block_type_id: 0,
+                after_block_type_id,
+            },
+        };
+        let fields_of = |res: Result<String, Error>| match res.map_err(|e| e.error_type) {
+            Err(ErrorType::Validation(details)) => {
+                details.into_iter().map(|d| d.field).collect::<Vec<_>>()
+            }
+            _ => panic!("expected a validation error"),
+        };
+
+        let res =
+            TimeBlock::split_timeblock_by_id(&storage, &locks, split(at(9, 30), " ", 7), None)
+                .await;
+        assert_eq!(fields_of(res), ["after_title", "after_block_type_id"]);
+        let res =
+            TimeBlock::split_timeblock_by_id(&storage, &locks, split(at(10, 0), "after", 0), None)
+                .await;
+        assert_eq!(fields_of(res), ["split_time"]);
+        assert_eq!(check(storage.get_day_timeblocks(day).await), initial);
+    }
+
+    #[tokio::test]
+    async fn id_queries_find_their_day() {
+        let storage = MemoryStorage::default();
+        save_blocktypes(&storage).await;
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let next_day = day.succ_opt().unwrap();
+        let block = TimeBlock::new(
+            check(day_start(next_day)),
+            check(day_start(next_day)) + chrono::Duration::hours(1),
+            0,
+            "early".to_string(),
+        );
+        check(
+            storage
+                .save_day_timeblocks(next_day, std::slice::from_ref(&block))
+                .await,
+        );
+        check(storage.save_day_timeblocks(day, &[]).await);
+
+        let split = serde_json::from_value::<SplitTimeBlockByIdQuery>(serde_json::json!({
+            "id": block.id,
+            "split_time": block.start_time + chrono::Duration::minutes(30),
+            "before_title": "first",
+            "after_title": "second",
+            "before_block_type_id": 1,
+            "after_block_type_id": 2,
+        }))
+        .unwrap();
+        check(TimeBlock::split_timeblock_by_id(&storage, &locks, split, None).await);
+        let titles = check(storage.get_day_timeblocks(next_day).await)
+            .into_iter()
+            .map(|b| b.title)
+            .collect::<Vec<_>>();
+        assert_eq!(titles, ["first", "second"]);
+
+        let update = |day, id| UpdateTimeBlockQuery {
+            day,
+            id,
+            title: "renamed".to_string(),
+            block_type_id: 0,
+        };
+        for (hint, id) in [(None, Uuid::new_v4()), (Some(day), block.id)] {
+            let res = TimeBlock::update_timeblock(&storage, &locks, update(hint, id), None).await;
+            assert!(matches!(
+                res.map_err(|e| e.error_type),
+                Err(ErrorType::NotFound)
+            ));
+        }
+        check(TimeBlock::update_timeblock(&storage, &locks, update(None, block.id), None).await);
+        assert_eq!(
+            check(storage.get_day_timeblocks(next_day).await)[0].title,
+            "renamed"
+        );
+    }
+
+    #[tokio::test]
+    async fn multi_split_applies_all_or_nothing() {
+        let storage = MemoryStorage::default();
+        save_blocktypes(&storage).await;
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let initial = vec![TimeBlock::new(at(9, 0), at(11, 0), 0, "work".to_string())];
+        check(storage.save_day_timeblocks(day, &initial).await);
+
+        let split = |minutes: &[u32]| MultiSplitTimeBlockQuery {
+            start_time: at(9, 0),
+            end_time: at(11, 0),
+            before_title: "segment 0".to_string(),
+            before_block_type_id: 0,
+            splits: minutes
+                .iter()
+                .enumerate()
+                .map(|(i, m)| SplitPoint {
+                    split_time: at(9, 0) + chrono::Duration::minutes(i64::from(*m)),
+                    title: format!("segment {}", i + 1),
+                    block_type_id: 1 + (i % 2) as u8,
+                })
+                .collect(),
+        };
+
+        // The last point lies past the block's end.
+        let res = TimeBlock::split_timeblock_multi(&storage, &locks, split(&[30, 150]), None).await;
+        assert!(matches!(
+            res.map_err(|e| e.error_type),
+            Err(ErrorType::Validation(_))
+        ));
+        assert_eq!(check(storage.get_day_timeblocks(day).await), initial);
+
+        check(TimeBlock::split_timeblock_multi(&storage, &locks, split(&[30, 60, 90]), None).await);
+        let timeblocks = check(storage.get_day_timeblocks(day).await);
+        let spans = timeblocks
+            .iter()
+            .map(|b| (b.title.as_str(), b.block_type_id, b.start_time, b.end_time))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                ("segment 0", 0, at(9, 0), at(9, 30)),
+                ("segment 1", 1, at(9, 30), at(10, 0)),
+                ("segment 2", 2, at(10, 0), at(10, 30)),
+                ("segment 3", 1, at(10, 30), at(11, 0)),
+            ]
+        );
+        assert_eq!(timeblocks[0].id, initial[0].id);
     }
 }
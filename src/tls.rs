@@ -0,0 +1,134 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    http::{header, uri::Authority, HeaderMap, StatusCode, Uri},
+    response::Redirect,
+    routing::any,
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::{
+    config::TlsConfig,
+    err::{Error, ErrorType},
+    err_from_type,
+};
+
+/// How often the certificate and key files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Load the certificate and key, and keep reloading them whenever either
+/// file changes so renewed certificates are picked up without a restart.
+pub async fn load(config: &TlsConfig) -> Result<RustlsConfig, Box<dyn std::error::Error>> {
+    let rustls = RustlsConfig::from_pem_file(&config.cert, &config.key)
+        .await
+        .map_err(|e| {
+            format!(
+                "Loading TLS certificate {} and key {}: {}",
+                config.cert.display(),
+                config.key.display(),
+                e
+            )
+        })?;
+    tokio::spawn(watch(
+        rustls.clone(),
+        config.cert.clone(),
+        config.key.clone(),
+    ));
+    Ok(rustls)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+async fn watch(rustls: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    let mut loaded = (modified(&cert), modified(&key));
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let current = (modified(&cert), modified(&key));
+        if current == loaded {
+            continue;
+        }
+        // A failed reload, e.g. while only one of the files has been
+        // replaced, keeps the old certificate and is retried next tick.
+        match rustls.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => {
+                tracing::info!(cert = %cert.display(), "Reloaded TLS certificate");
+                loaded = current;
+            }
+            Err(e) => {
+                tracing::warn!(cert = %cert.display(), error = %e, "Reloading TLS certificate failed")
+            }
+        }
+    }
+}
+
+/// Routes answering every plain HTTP request with a permanent redirect to the
+/// same path over HTTPS on `https_port`.
+pub fn redirect_routes(https_port: u16) -> Router {
+    Router::new().fallback(any(move |headers: HeaderMap, uri: Uri| async move {
+        redirect(https_port, &headers, &uri)
+    }))
+}
+
+fn redirect(https_port: u16, headers: &HeaderMap, uri: &Uri) -> Result<Redirect, Error> {
+    let authority = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .ok_or_else(|| {
+            err_from_type!(
+                ErrorType::Rejection(StatusCode::BAD_REQUEST),
+                "Missing or invalid Host header"
+            )
+        })?;
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = if https_port == 443 {
+        format!("https://{}{}", authority.host(), path)
+    } else {
+        format!("https://{}:{}{}", authority.host(), https_port, path)
+    };
+    Ok(Redirect::permanent(&location))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use axum::{http::HeaderValue, response::IntoResponse};
+
+    use super::*;
+
+    fn check(https_port: u16, host: &str, uri: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+        let response = redirect(https_port, &headers, &uri.parse().unwrap())
+            .ok()
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn redirect_keeps_host_and_path() {
+        assert_eq!(
+            check(8443, "time.example:8080", "/timeblock?day=1"),
+            "https://time.example:8443/timeblock?day=1"
+        );
+        assert_eq!(
+            check(443, "time.example", "/state"),
+            "https://time.example/state"
+        );
+        assert_eq!(check(443, "[::1]:80", "/"), "https://[::1]/");
+        assert!(redirect(443, &HeaderMap::new(), &"/".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+use std::fmt::{self, Display};
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    err::Error,
+    locks::DayLocks,
+    storage::Storage,
+    timeblock::{day_end, day_start, TimeBlock},
+};
+
+/// Something wrong with the recorded timeline.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Anomaly {
+    /// The day's blocks are not ordered by start time.
+    Unsorted,
+    /// A block ending before it starts.
+    NegativeDuration { id: Uuid },
+    /// A block ending when it starts.
+    EmptyBlock { id: Uuid },
+    /// A block starting before the previous one ended.
+    Overlap { first: Uuid, second: Uuid },
+    /// Untracked time between two blocks.
+    Gap {
+        after: Uuid,
+        before: Uuid,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    },
+    /// A block filed under a day it does not start on.
+    WrongDay { id: Uuid },
+    /// A block running past the end of its day instead of being cut at midnight.
+    PastMidnight { id: Uuid },
+    /// The previous day was cut at midnight but this day does not continue at
+    /// midnight.
+    BrokenMidnight { previous: Uuid },
+}
+
+impl Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::Unsorted => write!(f, "Blocks are not sorted by start time"),
+            Anomaly::NegativeDuration { id } => write!(f, "Block {} ends before it starts", id),
+            Anomaly::EmptyBlock { id } => write!(f, "Block {} is empty", id),
+            Anomaly::Overlap { first, second } => {
+                write!(f, "Block {} overlaps block {}", second, first)
+            }
+            Anomaly::Gap {
+                after,
+                before,
+                from,
+                to,
+            } => write!(
+                f,
+                "Gap from {} to {} between blocks {} and {}",
+                from.format("%H:%M:%S"),
+                to.format("%H:%M:%S"),
+                after,
+                before
+            ),
+            Anomaly::WrongDay { id } => write!(f, "Block {} starts on another day", id),
+            Anomaly::PastMidnight { id } => write!(f, "Block {} runs past midnight", id),
+            Anomaly::BrokenMidnight { previous } => write!(
+                f,
+                "Block {} was cut at midnight but the day does not continue at midnight",
+                previous
+            ),
+        }
+    }
+}
+
+/// One change a repair made to a day.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Repair {
+    /// An empty block, or one lying entirely within an earlier one, was
+    /// removed. The whole block is kept here so it can be restored by hand.
+    Removed { block: TimeBlock },
+    /// A block overlapping the previous one now starts where it ends.
+    Trimmed {
+        id: Uuid,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    },
+}
+
+impl Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Repair::Removed { block } => write!(
+                f,
+                "Removed block {} \"{}\" from {} to {}",
+                block.id,
+                block.title,
+                block.start_time.format("%H:%M:%S"),
+                block.end_time.format("%H:%M:%S")
+            ),
+            Repair::Trimmed { id, from, to } => write!(
+                f,
+                "Moved start of block {} from {} to {}",
+                id,
+                from.format("%H:%M:%S"),
+                to.format("%H:%M:%S")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DayReport {
+    pub day: NaiveDate,
+    pub anomalies: Vec<Anomaly>,
+    /// Whether the day was rewritten by a repair.
+    pub fixed: bool,
+    /// What the repair changed.
+    pub repairs: Vec<Repair>,
+    /// Anomalies still in the stored day, which need a human to resolve.
+    pub remaining: Vec<Anomaly>,
+}
+
+/// Problems in one day's blocks. `previous_last` is the last block of the day
+/// before, used to check blocks continued over midnight.
+pub fn check_day(
+    day: NaiveDate,
+    timeblocks: &[TimeBlock],
+    previous_last: Option<&TimeBlock>,
+) -> Result<Vec<Anomaly>, Error> {
+    let mut anomalies = Vec::new();
+    if !timeblocks.is_sorted_by_key(|b| b.start_time) {
+        anomalies.push(Anomaly::Unsorted);
+    }
+
+    let end = day_end(day)?;
+    for block in timeblocks {
+        if block.end_time < block.start_time {
+            anomalies.push(Anomaly::NegativeDuration { id: block.id });
+        } else if block.end_time == block.start_time {
+            anomalies.push(Anomaly::EmptyBlock { id: block.id });
+        }
+        if block.start_time.date_naive() != day {
+            anomalies.push(Anomaly::WrongDay { id: block.id });
+        } else if block.end_time > end {
+            anomalies.push(Anomaly::PastMidnight { id: block.id });
+        }
+    }
+
+    let mut sorted = timeblocks.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|b| b.start_time);
+    for pair in sorted.windows(2) {
+        let (first, second) = (pair[0], pair[1]);
+        if second.start_time < first.end_time {
+            anomalies.push(Anomaly::Overlap {
+                first: first.id,
+                second: second.id,
+            });
+        } else if second.start_time > first.end_time {
+            anomalies.push(Anomaly::Gap {
+                after: first.id,
+                before: second.id,
+                from: first.end_time,
+                to: second.start_time,
+            });
+        }
+    }
+
+    if let (Some(previous), Some(first)) = (previous_last, sorted.first()) {
+        let previous_day = previous.start_time.date_naive();
+        if day.pred_opt() == Some(previous_day)
+            && previous.end_time == day_end(previous_day)?
+            && first.start_time != day_start(day)?
+        {
+            anomalies.push(Anomaly::BrokenMidnight {
+                previous: previous.id,
+            });
+        }
+    }
+    Ok(anomalies)
+}
+
+/// Sort the blocks, drop empty ones and trim every block to start no earlier
+/// than the previous one ends, dropping those that lie entirely within it.
+/// Blocks ending before they start, gaps and midnight problems need a human
+/// to decide and are left alone.
+pub fn repair_day(mut timeblocks: Vec<TimeBlock>) -> (Vec<TimeBlock>, Vec<Repair>) {
+    timeblocks.sort_by_key(|b| b.start_time);
+
+    let mut repaired = Vec::with_capacity(timeblocks.len());
+    let mut repairs = Vec::new();
+    let mut last_end = None;
+    for mut block in timeblocks {
+        if block.end_time < block.start_time {
+            repaired.push(block);
+            continue;
+        }
+        let start_time = last_end.map_or(block.start_time, |end| block.start_time.max(end));
+        if block.end_time <= start_time {
+            repairs.push(Repair::Removed { block });
+            continue;
+        }
+        if start_time != block.start_time {
+            repairs.push(Repair::Trimmed {
+                id: block.id,
+                from: block.start_time,
+                to: start_time,
+            });
+            block.start_time = start_time;
+        }
+        last_end = Some(block.end_time);
+        repaired.push(block);
+    }
+    (repaired, repairs)
+}
+
+/// Check every recorded day, repairing the ones with anomalies if `fix` is
+/// set. Days without anomalies are left out of the report.
+pub async fn validate(
+    storage: &dyn Storage,
+    locks: &DayLocks,
+    fix: bool,
+) -> Result<Vec<DayReport>, Error> {
+    let mut reports = Vec::new();
+    let mut previous_last: Option<TimeBlock> = None;
+    for day in storage.list_days().await? {
+        let _guard = locks.lock(&[day]).await?;
+        let timeblocks = storage.get_day_timeblocks(day).await?;
+        let anomalies = check_day(day, &timeblocks, previous_last.as_ref())?;
+        let mut fixed = false;
+        let mut repairs = Vec::new();
+        let mut remaining = anomalies.clone();
+        let timeblocks = if fix && !anomalies.is_empty() {
+            let (repaired, day_repairs) = repair_day(timeblocks.clone());
+            if repaired != timeblocks {
+                storage.save_day_timeblocks(day, &repaired).await?;
+                for repair in &day_repairs {
+                    tracing::warn!(day = %day, "{}", repair);
+                }
+                fixed = true;
+                repairs = day_repairs;
+                remaining = check_day(day, &repaired, previous_last.as_ref())?;
+            }
+            repaired
+        } else {
+            timeblocks
+        };
+        previous_last = timeblocks.into_iter().max_by_key(|b| b.start_time);
+        if !anomalies.is_empty() {
+            reports.push(DayReport {
+                day,
+                anomalies,
+                fixed,
+                repairs,
+                remaining,
+            });
+        }
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::memory::MemoryStorage,
+        testing::{at, check},
+    };
+
+    #[tokio::test]
+    async fn anomalies_are_reported_and_fixed() {
+        let storage = MemoryStorage::default();
+        let locks = DayLocks::default();
+        let day = at(0, 0).date_naive();
+        let a = TimeBlock::new(at(9, 0), at(10, 0), 0, "a".to_string());
+        let b = TimeBlock::new(at(8, 0), at(9, 30), 0, "b".to_string());
+        let c = TimeBlock::new(at(12, 0), at(11, 0), 0, "c".to_string());
+        let d = TimeBlock::new(at(13, 0), at(14, 0), 0, "d".to_string());
+        let e = TimeBlock::new(at(13, 15), at(13, 45), 0, "e".to_string());
+        let blocks = vec![a.clone(), b.clone(), c.clone(), d.clone(), e.clone()];
+        check(storage.save_day_timeblocks(day, &blocks).await);
+
+        let anomalies = check(check_day(day, &blocks, None));
+        assert!(anomalies.contains(&Anomaly::Unsorted));
+        assert!(anomalies.contains(&Anomaly::NegativeDuration { id: c.id }));
+        assert!(anomalies.contains(&Anomaly::Overlap {
+            first: b.id,
+            second: a.id,
+        }));
+
+        let reports = check(validate(&storage, &locks, true).await);
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].fixed);
+        assert_eq!(
+            reports[0].repairs,
+            [
+                Repair::Trimmed {
+                    id: a.id,
+                    from: at(9, 0),
+                    to: at(9, 30),
+                },
+                Repair::Removed { block: e },
+            ]
+        );
+        assert!(reports[0]
+            .remaining
+            .contains(&Anomaly::NegativeDuration { id: c.id }));
+        assert!(!reports[0]
+            .remaining
+            .iter()
+            .any(|anomaly| matches!(anomaly, Anomaly::Overlap { .. } | Anomaly::Unsorted)));
+
+        let repaired = check(storage.get_day_timeblocks(day).await);
+        let spans = repaired
+            .iter()
+            .map(|b| (b.id, b.start_time, b.end_time))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                (b.id, at(8, 0), at(9, 30)),
+                (a.id, at(9, 30), at(10, 0)),
+                (c.id, at(12, 0), at(11, 0)),
+                (d.id, at(13, 0), at(14, 0)),
+            ]
+        );
+        // The inverted block is left for a human to look at.
+        let anomalies = check(check_day(day, &repaired, None));
+        assert_eq!(anomalies, reports[0].remaining);
+    }
+}
@@ -0,0 +1,66 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::{
+    blocktype::BlockType,
+    err::{Error, ErrorType},
+    err_from_type,
+};
+
+/// One problem with one field of a request.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Collects everything wrong with a request so the client sees all of it in
+/// one response.
+#[derive(Debug, Default)]
+pub struct Violations(Vec<FieldError>);
+
+impl Violations {
+    pub fn push(&mut self, field: &str, message: String) {
+        self.0.push(FieldError {
+            field: field.to_string(),
+            message,
+        });
+    }
+
+    pub fn title(&mut self, field: &str, title: &str) {
+        if title.trim().is_empty() {
+            self.push(field, "must not be empty".to_string());
+        }
+    }
+
+    pub fn block_type(&mut self, field: &str, id: u8, blocktypes: &[BlockType]) {
+        if !blocktypes.iter().any(|b| b.id == id) {
+            self.push(field, format!("block type {} does not exist", id));
+        }
+    }
+
+    /// `end` must come strictly after `start`. The problem is reported
+    /// against `end_field`.
+    pub fn time_order(
+        &mut self,
+        (start_field, start): (&str, DateTime<Local>),
+        (end_field, end): (&str, DateTime<Local>),
+    ) {
+        if end <= start {
+            self.push(end_field, format!("must be after {}", start_field));
+        }
+    }
+
+    pub fn into_result(self) -> Result<(), Error> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let summary = self
+            .0
+            .iter()
+            .map(|e| format!("{} {}", e.field, e.message))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Err(err_from_type!(ErrorType::Validation(self.0), "{}", summary))
+    }
+}